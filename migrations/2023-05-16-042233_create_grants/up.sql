@@ -0,0 +1,39 @@
+-- Your SQL goes here
+CREATE TABLE grants
+(
+    id         BIGSERIAL                NOT NULL PRIMARY KEY,
+    src_ip     inet                     NOT NULL,
+    dst_ip     inet                     NOT NULL,
+    group_id   TEXT                     NOT NULL,
+    rule_id    TEXT                     NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    added_on   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT grants_added_fk FOREIGN KEY (src_ip, dst_ip) REFERENCES added (src_ip, dst_ip)
+);
+
+CREATE INDEX ON grants (src_ip, dst_ip);
+CREATE INDEX ON grants (expires_at);
+
+-- Added rows are only removed once their grants have been revoked
+CREATE OR REPLACE PROCEDURE clean_db()
+    LANGUAGE SQL
+AS
+$$
+-- Clean added
+DELETE
+FROM blocks
+WHERE event_ts < NOW() - '1 day'::INTERVAL
+   OR insert_ts < NOW() - '1 day'::INTERVAL ;
+
+-- Clean added
+DELETE
+FROM added a
+WHERE a.added_on < NOW() - '1 day'::INTERVAL
+  AND NOT EXISTS (SELECT 1 FROM grants g WHERE g.src_ip = a.src_ip AND g.dst_ip = a.dst_ip);
+
+-- Clean denies
+DELETE
+FROM denies
+WHERE added_on < NOW() - '1 day'::INTERVAL;
+$$;
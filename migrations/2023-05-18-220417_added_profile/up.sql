@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE added
+    ADD COLUMN profile TEXT NOT NULL DEFAULT 'default';
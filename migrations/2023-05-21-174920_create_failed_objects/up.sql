@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE failed_objects
+(
+    id          BIGSERIAL                NOT NULL PRIMARY KEY,
+    bucket      TEXT                     NOT NULL,
+    key         TEXT                     NOT NULL,
+    error_class TEXT                     NOT NULL,
+    error       TEXT                     NOT NULL,
+    failed_on   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON failed_objects (bucket, key);
+CREATE INDEX ON failed_objects (failed_on);
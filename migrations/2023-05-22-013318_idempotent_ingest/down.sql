@@ -0,0 +1,29 @@
+-- This file should undo anything in `up.sql`
+CREATE OR REPLACE PROCEDURE clean_db()
+    LANGUAGE SQL
+AS
+$$
+-- Clean added
+DELETE
+FROM blocks
+WHERE event_ts < NOW() - '1 day'::INTERVAL
+   OR insert_ts < NOW() - '1 day'::INTERVAL ;
+
+-- Clean added
+DELETE
+FROM added a
+WHERE a.added_on < NOW() - '1 day'::INTERVAL
+  AND NOT EXISTS (SELECT 1 FROM grants g WHERE g.src_ip = a.src_ip AND g.dst_ip = a.dst_ip);
+
+-- Clean denies
+DELETE
+FROM denies
+WHERE added_on < NOW() - '1 day'::INTERVAL;
+$$;
+
+DROP INDEX unique_block_idx;
+
+ALTER TABLE blocks
+    DROP COLUMN src_port;
+
+DROP TABLE ingested_objects;
@@ -0,0 +1,48 @@
+-- Your SQL goes here
+CREATE TABLE ingested_objects
+(
+    bucket      TEXT                     NOT NULL,
+    key         TEXT                     NOT NULL,
+    etag        TEXT                     NOT NULL,
+    ingested_on TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    CONSTRAINT ingested_objects_pk PRIMARY KEY (bucket, key, etag)
+);
+
+CREATE INDEX ON ingested_objects (ingested_on);
+
+-- Every knock is its own flow, so its own source port; without it two knocks on the same port in
+-- the same second look like a re-delivered record. Rows from before this (and logs without a
+-- srcport field) have none and are only deduped by ingested_objects.
+ALTER TABLE blocks
+    ADD COLUMN src_port INT4;
+
+CREATE UNIQUE INDEX unique_block_idx ON blocks (src_ip, dst_ip, proto, src_port, port, event_ts)
+    WHERE src_port IS NOT NULL;
+
+CREATE OR REPLACE PROCEDURE clean_db()
+    LANGUAGE SQL
+AS
+$$
+-- Clean added
+DELETE
+FROM blocks
+WHERE event_ts < NOW() - '1 day'::INTERVAL
+   OR insert_ts < NOW() - '1 day'::INTERVAL ;
+
+-- Clean added
+DELETE
+FROM added a
+WHERE a.added_on < NOW() - '1 day'::INTERVAL
+  AND NOT EXISTS (SELECT 1 FROM grants g WHERE g.src_ip = a.src_ip AND g.dst_ip = a.dst_ip);
+
+-- Clean denies
+DELETE
+FROM denies
+WHERE added_on < NOW() - '1 day'::INTERVAL;
+
+-- Clean ingested objects; s3 redelivers within hours, not weeks
+DELETE
+FROM ingested_objects
+WHERE ingested_on < NOW() - '7 days'::INTERVAL;
+$$;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX grants_group_id_rule_id_idx;
+
+ALTER TABLE grants
+    DROP COLUMN kind;
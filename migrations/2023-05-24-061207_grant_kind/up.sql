@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- What a grant's group_id/rule_id refer to: a security group rule, or a managed prefix list entry
+ALTER TABLE grants
+    ADD COLUMN kind TEXT NOT NULL DEFAULT 'sg-rule';
+
+-- Prefix list entries are shared by every grant for the same src
+CREATE INDEX ON grants (group_id, rule_id);
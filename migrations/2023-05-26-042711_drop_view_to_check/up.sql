@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- The candidate query is built by pknocker so its window, counts and excluded ports can follow the
+-- config and knock profiles
+DROP VIEW view_to_check;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- The end of the flow log aggregation interval the packet was seen in; event_ts is the start
+ALTER TABLE blocks
+    ADD COLUMN event_end TIMESTAMP WITH TIME ZONE;
+
+UPDATE blocks
+SET event_end = event_ts;
+
+ALTER TABLE blocks
+    ALTER COLUMN event_end SET NOT NULL;
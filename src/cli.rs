@@ -0,0 +1,238 @@
+use std::path::PathBuf;
+
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::AsyncPgConnection;
+use lambda_runtime::{run, service_fn, Error};
+use tracing::log::info;
+
+use crate::dry_run::DryRun;
+use crate::grant::Backend;
+use crate::knock::{KnockSecret, Profile};
+
+pub const USAGE: &str = "\
+usage: pknocker-stream <command>
+
+commands:
+  lambda                          run as the lambda function (the default inside lambda)
+  ingest [--dry-run] <file>       add the blocks from a parquet or text flow log; a dry run
+                                  also checks them along with the db's
+  check [--dry-run]               run the knock checks against the blocks in the db
+  clean                           expire grants and clean out old rows
+  migrate                         apply pending migrations and check the schema
+  seed-test-data                  insert test blocks and denies
+  print-wanted                    print the compiled in knock sequence as json
+  grants list [<kind> <group>]    list the grants in the db, or the ones live in a backend
+  grants revoke <id>              revoke a grant now
+  replay [--profiles <file>] <dir>
+                                  run a directory of parquet flow logs through the checks in
+                                  memory; profiles are the knock secret's json";
+
+pub enum Command {
+    Lambda,
+    Ingest {
+        file: PathBuf,
+        dry_run: bool,
+    },
+    Check {
+        dry_run: bool,
+    },
+    Clean,
+    Migrate,
+    SeedTestData,
+    PrintWanted,
+    GrantsList {
+        live: Option<(Backend, String)>,
+    },
+    GrantsRevoke {
+        id: i64,
+    },
+    Replay {
+        dir: PathBuf,
+        profiles: Option<PathBuf>,
+    },
+}
+
+impl Command {
+    /// Lambda mode when running in lambda, whatever the args
+    pub fn from_env() -> Result<Command, String> {
+        if std::env::var_os("AWS_LAMBDA_RUNTIME_API").is_some() {
+            return Ok(Command::Lambda);
+        }
+        Command::parse(std::env::args().skip(1).collect())
+    }
+
+    fn parse(mut args: Vec<String>) -> Result<Command, String> {
+        let profiles = match args.iter().position(|a| a == "--profiles") {
+            Some(idx) if idx + 1 < args.len() => Some(PathBuf::from(
+                args.drain(idx..=idx + 1).next_back().unwrap_or_default(),
+            )),
+            Some(_) => return Err("--profiles needs a file".to_string()),
+            None => None,
+        };
+        let dry_run = args.iter().any(|a| a == "--dry-run");
+        let args: Vec<&str> = args
+            .iter()
+            .map(String::as_str)
+            .filter(|a| *a != "--dry-run")
+            .collect();
+
+        let command = match args.as_slice() {
+            ["lambda"] => Command::Lambda,
+            ["ingest", file] => Command::Ingest {
+                file: PathBuf::from(file),
+                dry_run,
+            },
+            ["check"] => Command::Check { dry_run },
+            ["clean"] => Command::Clean,
+            ["migrate"] => Command::Migrate,
+            ["seed-test-data"] => Command::SeedTestData,
+            ["print-wanted"] => Command::PrintWanted,
+            ["grants", "list"] => Command::GrantsList { live: None },
+            ["grants", "list", kind, group_id] => Command::GrantsList {
+                live: Some((kind.parse()?, group_id.to_string())),
+            },
+            ["grants", "revoke", id] => Command::GrantsRevoke {
+                id: id.parse().map_err(|_| format!("bad grant id {id:?}"))?,
+            },
+            ["replay", dir] => Command::Replay {
+                dir: PathBuf::from(dir),
+                profiles: profiles.clone(),
+            },
+            [] => return Err("missing command".to_string()),
+            _ => return Err(format!("unknown command {:?}", args.join(" "))),
+        };
+
+        let takes_dry_run = matches!(command, Command::Ingest { .. } | Command::Check { .. });
+        if dry_run && !takes_dry_run {
+            return Err("--dry-run only applies to ingest and check".to_string());
+        }
+
+        if profiles.is_some() && !matches!(command, Command::Replay { .. }) {
+            return Err("--profiles only applies to replay".to_string());
+        }
+
+        Ok(command)
+    }
+
+    pub async fn run(self) -> Result<(), Error> {
+        match self {
+            Command::Lambda => run(service_fn(crate::function_handler)).await,
+
+            Command::Ingest { file, dry_run } => {
+                let (pool, profiles) = setup().await?;
+                let data = tokio::fs::read(&file).await?;
+                let dry_run = dry_run.then(DryRun::default);
+
+                crate::s3::add_data(&file.to_string_lossy(), data, &pool, dry_run.as_ref()).await?;
+                // What the lambda would do with the blocks once ingested, without doing it
+                if let Some(dry_run) = &dry_run {
+                    crate::db::run_checks(&pool, &profiles, Some(dry_run)).await?;
+                }
+                print_report(dry_run)
+            }
+
+            Command::Check { dry_run } => {
+                let (pool, profiles) = setup().await?;
+                let dry_run = dry_run.then(DryRun::default);
+
+                crate::db::run_checks(&pool, &profiles, dry_run.as_ref()).await?;
+                print_report(dry_run)
+            }
+
+            Command::Clean => {
+                let (pool, _) = setup().await?;
+                crate::db::expire_grants(&pool).await?;
+                crate::db::clean(&pool).await?;
+                Ok(())
+            }
+
+            Command::Migrate => {
+                let (pool, _) = setup().await?;
+                let applied = crate::migrate::run(&pool).await?;
+                if applied.is_empty() {
+                    info!("Nothing to migrate");
+                }
+                for name in applied {
+                    println!("{name}");
+                }
+                Ok(())
+            }
+
+            Command::SeedTestData => {
+                let (pool, _) = setup().await?;
+                crate::db::insert_test_data(&pool).await?;
+                Ok(())
+            }
+
+            Command::PrintWanted => crate::print_wanted().await,
+
+            Command::GrantsList { live: None } => {
+                let (pool, _) = setup().await?;
+                for grant in crate::db::list_grants(&pool).await? {
+                    println!(
+                        "{}\t{} -> {}\t{}\t{}\t{}\texpires {}",
+                        grant.id,
+                        grant.src_ip,
+                        grant.dst_ip,
+                        grant.kind,
+                        grant.group_id,
+                        grant.rule_id,
+                        grant.expires_at
+                    );
+                }
+                Ok(())
+            }
+
+            Command::GrantsList {
+                live: Some((backend, group_id)),
+            } => {
+                let (pool, _) = setup().await?;
+                for listed in backend.get().list(&group_id, &pool).await? {
+                    println!(
+                        "{}\t{}\t{}",
+                        listed.src, listed.rule.group_id, listed.rule.rule_id
+                    );
+                }
+                Ok(())
+            }
+
+            Command::GrantsRevoke { id } => {
+                let (pool, _) = setup().await?;
+                if crate::db::revoke_grant(id, &pool).await? {
+                    info!("Revoked grant {id}");
+                    Ok(())
+                } else {
+                    Err(format!("no grant {id} was revoked").into())
+                }
+            }
+
+            Command::Replay { dir, profiles } => {
+                let profiles = match profiles {
+                    Some(file) => {
+                        serde_json::from_slice::<KnockSecret>(&tokio::fs::read(file).await?)?
+                            .into_profiles()
+                    }
+                    None => setup().await?.1,
+                };
+
+                let report = crate::replay::replay(&dir, &profiles).await?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn setup() -> Result<(Pool<AsyncPgConnection>, Vec<Profile>), Error> {
+    let (db_conn_info, profiles) = crate::secrets::get_conn_info().await?;
+    let profiles = crate::wanted_profiles(profiles)?;
+    let pool = crate::db::get_pool(db_conn_info).await?;
+    Ok((pool, profiles))
+}
+
+fn print_report(dry_run: Option<DryRun>) -> Result<(), Error> {
+    if let Some(dry_run) = dry_run {
+        println!("{}", serde_json::to_string_pretty(&dry_run.into_report())?);
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::env;
+use std::str::FromStr;
+
+use once_cell::sync::Lazy;
+use tracing::log::warn;
+
+use crate::ec2::GrantGroups;
+use crate::grant::Backend;
+use crate::knock::{KnockOrder, Ties};
+
+pub struct Config {
+    pub order: KnockOrder,
+    /// How far before a pair's latest knock its other knocks still count
+    pub knock_window_secs: i64,
+    /// How close together the knocks of a matching sequence have to have happened
+    pub knock_span_secs: i64,
+    /// Default lifetime of opened rules, overridable per instance with the `pknocker:ttl` tag
+    pub grant_ttl_secs: i64,
+    /// How long the instance map is reused before it's loaded again
+    pub instance_cache_secs: u64,
+    /// Only instances with this tag (`key=value`) can have rules opened; every instance with a
+    /// security group when unset
+    pub enroll_tag: Option<(String, String)>,
+    pub grant_groups: GrantGroups,
+    /// How knockers are let in, unless the instance's `pknocker:backend` tag says otherwise
+    pub grant_backend: Backend,
+    /// Prefix lists for instances without their own `pknocker:prefix-list` tags; unset leaves
+    /// those instances without a prefix list to grant in
+    pub prefix_list_v4: Option<String>,
+    pub prefix_list_v6: Option<String>,
+    /// Inbound NACL rule numbers the nacl backend may use; they need to come before any denies
+    pub nacl_rules: (i32, i32),
+    /// Where nftables updates are written: `s3://bucket/prefix` or a directory. There's no SQS
+    /// outbox; s3 event notifications can forward the objects to a queue.
+    pub nft_outbox: Option<String>,
+    /// Report what would be done instead of doing it; an event can also ask with `"dry_run": true`
+    pub dry_run: bool,
+    /// Apply pending migrations on a cold start; the schema is checked against `schema.rs` either way
+    pub migrate: bool,
+    /// Use the compiled in sequence when the knock profiles can't be loaded from secrets
+    pub allow_builtin_conns: bool,
+}
+
+pub static CONFIG: Lazy<Config> = Lazy::new(|| Config {
+    order: match env_or("PKNOCKER_ORDER", "ordered".to_string()).as_str() {
+        "unordered" => KnockOrder::Unordered,
+        other => {
+            if other != "ordered" {
+                warn!("Unknown PKNOCKER_ORDER {other}; using ordered");
+            }
+            KnockOrder::Ordered {
+                tie_secs: env_or("PKNOCKER_TIE_SECS", 0),
+                ties: match env_or("PKNOCKER_TIES", "any".to_string()).as_str() {
+                    "reject" => Ties::Reject,
+                    _ => Ties::AnyOrder,
+                },
+            }
+        }
+    },
+    knock_window_secs: env_or("PKNOCKER_KNOCK_WINDOW_SECS", 600),
+    knock_span_secs: env_or("PKNOCKER_KNOCK_SPAN_SECS", 60),
+    grant_ttl_secs: env_or("PKNOCKER_GRANT_TTL_SECS", 3600),
+    instance_cache_secs: env_or("PKNOCKER_INSTANCE_CACHE_SECS", 300),
+    enroll_tag: match env_or("PKNOCKER_ENROLL_TAG", "pknocker=enabled".to_string()).as_str() {
+        "" | "none" => None,
+        tag => match tag.split_once('=') {
+            Some((key, value)) => Some((key.to_string(), value.to_string())),
+            None => {
+                warn!("PKNOCKER_ENROLL_TAG {tag:?} isn't key=value; using pknocker=enabled");
+                Some(("pknocker".to_string(), "enabled".to_string()))
+            }
+        },
+    },
+    grant_groups: match env_or("PKNOCKER_GRANT_GROUPS", "shared".to_string()).as_str() {
+        "dedicated" => GrantGroups::Dedicated {
+            create: env_or("PKNOCKER_CREATE_KNOCK_GROUP", false),
+        },
+        other => {
+            if other != "shared" {
+                warn!("Unknown PKNOCKER_GRANT_GROUPS {other}; using shared");
+            }
+            GrantGroups::Shared
+        }
+    },
+    grant_backend: env_or("PKNOCKER_GRANT_BACKEND", Backend::SgRules),
+    prefix_list_v4: env::var("PKNOCKER_PREFIX_LIST").ok(),
+    prefix_list_v6: env::var("PKNOCKER_PREFIX_LIST_V6").ok(),
+    nacl_rules: match env_or("PKNOCKER_NACL_RULES", "1-99".to_string()).split_once('-') {
+        Some((first, last)) => match (first.trim().parse(), last.trim().parse()) {
+            (Ok(first), Ok(last)) if 0 < first && first <= last && last < 32767 => (first, last),
+            _ => {
+                warn!("Invalid PKNOCKER_NACL_RULES; using 1-99");
+                (1, 99)
+            }
+        },
+        None => {
+            warn!("PKNOCKER_NACL_RULES isn't first-last; using 1-99");
+            (1, 99)
+        }
+    },
+    nft_outbox: env::var("PKNOCKER_NFT_OUTBOX").ok(),
+    dry_run: env_or("PKNOCKER_DRY_RUN", false),
+    migrate: env_or("PKNOCKER_MIGRATE", true),
+    allow_builtin_conns: env_or("PKNOCKER_ALLOW_BUILTIN_CONNS", false),
+});
+
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Err(_) => default,
+        Ok(val) => match val.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Couldn't parse {name}={val:?}; using the default");
+                default
+            }
+        },
+    }
+}
@@ -1,23 +1,66 @@
+use std::collections::HashSet;
+use std::future::Future;
 use std::io::Cursor;
+use std::net::IpAddr;
 use std::str::FromStr;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Text};
 use diesel_async::pooled_connection::deadpool::Pool;
 use diesel_async::pooled_connection::AsyncDieselConnectionManager;
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 use futures_util::future::BoxFuture;
 use futures_util::FutureExt;
 use ipnetwork::IpNetwork;
-use lambda_runtime::Error;
 use once_cell::sync::Lazy;
 use tokio_postgres_rustls::MakeRustlsConnect;
 use tracing::log::{error, info, warn};
 
+use crate::config::CONFIG;
+use crate::dry_run::{DryRun, ReportAllow};
+use crate::ec2::InstanceInfo;
+use crate::error::Error;
+use crate::grant::{AddedRule, Backend};
+use crate::knock::{event_time, judge, CheckWindow, Profile, Verdict};
 use crate::models::*;
+use crate::replay::Store;
 use crate::schema::*;
 use crate::secrets::DbConnSecret;
 
+/// The pairs with a count of recent knocks worth checking. Each conn is `[proto, port, start epoch
+/// secs, end epoch secs]`, ordered by when it was seen so the knock order can be enforced.
+///
+/// `$1` is the window in seconds, `$2` the excluded `proto/port`s, `$3`/`$4` the min and max
+/// knock counts and `$5` the excluded `dst/proto/port`s; see [`CheckWindow`].
+const TO_CHECK: &str = "
+WITH recent AS (SELECT b.id,
+                       b.src_ip,
+                       b.dst_ip,
+                       b.proto,
+                       b.port,
+                       b.event_ts,
+                       b.event_end,
+                       MAX(b.event_ts) OVER (PARTITION BY b.src_ip, b.dst_ip) AS latest_ts
+                FROM blocks b
+                         LEFT OUTER JOIN denies d ON b.src_ip = d.ip
+                         LEFT OUTER JOIN added a ON b.src_ip = a.src_ip AND b.dst_ip = a.dst_ip
+                WHERE d.ip IS NULL
+                  AND a.dst_ip IS NULL
+                  AND NOT (b.proto::TEXT || '/' || b.port) = ANY ($2)
+                  AND NOT (HOST(b.dst_ip) || '/' || b.proto::TEXT || '/' || b.port) = ANY ($5))
+SELECT src_ip,
+       dst_ip,
+       JSON_AGG(JSON_BUILD_ARRAY(proto, port, EXTRACT(EPOCH FROM event_ts)::INT8,
+                                 EXTRACT(EPOCH FROM event_end)::INT8)
+                ORDER BY event_ts, id)::TEXT AS conns
+FROM recent
+WHERE event_ts >= latest_ts - MAKE_INTERVAL(secs => $1::FLOAT8)
+GROUP BY src_ip, dst_ip
+HAVING COUNT(*) BETWEEN $3 AND $4
+";
+
 static ROOT_CERT: Lazy<MakeRustlsConnect> = Lazy::new(|| {
     let mut root = rustls::RootCertStore::empty();
     let b = include_bytes!("us-east-1-bundle.pem");
@@ -31,7 +74,7 @@ static ROOT_CERT: Lazy<MakeRustlsConnect> = Lazy::new(|| {
     )
 });
 
-fn establish_connection(url: &str) -> BoxFuture<ConnectionResult<AsyncPgConnection>> {
+fn establish_connection(url: &str) -> BoxFuture<'_, ConnectionResult<AsyncPgConnection>> {
     let fut = async {
         let (client, conn) = tokio_postgres::connect(url, ROOT_CERT.clone())
             .await
@@ -56,7 +99,7 @@ pub async fn get_pool(db_conn_info: DbConnSecret) -> Result<Pool<AsyncPgConnecti
         establish_connection,
     );
 
-    Ok(Pool::builder(mgr).max_size(2).build()?)
+    Pool::builder(mgr).max_size(2).build().map_err(Error::db)
 }
 
 pub async fn clean(pool: &Pool<AsyncPgConnection>) -> Result<(), Error> {
@@ -68,67 +111,504 @@ pub async fn clean(pool: &Pool<AsyncPgConnection>) -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn run_checks(pool: &Pool<AsyncPgConnection>) -> Result<(), Error> {
-    info!("Run checks");
+pub async fn add_blocks(
+    to_add: Vec<NewBlock>,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<(), Error> {
+    if !to_add.is_empty() {
+        let mut conn = pool.get().await?;
+
+        // Rows from a re-delivered object are already there
+        let res = diesel::insert_into(blocks::table)
+            .values(&to_add)
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .await;
+
+        if let Err(err) = res {
+            error!("Couldn't add {to_add:?}: {err:?}");
+            return Err(err.into());
+        }
+        info!("Added {to_add:?}")
+    }
+
+    Ok(())
+}
+
+pub async fn is_ingested(
+    obj: &NewIngestedObject,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<bool, Error> {
     let mut conn = pool.get().await?;
 
-    for to_check in view_to_check::table.load::<ViewToCheck>(&mut conn).await? {
-        let src = to_check.src_ip;
-
-        let mut conns = serde_json::from_str::<Conns>(&to_check.conns)?;
-        conns.0.sort();
-        if conns == *crate::WANTED_CONNS {
-            match crate::ec2::get_ip_map().await.get(&to_check.dst_ip) {
-                Some(info) => {
-                    if let Err(err) = crate::ec2::add_allow(to_check.src_ip, info).await {
-                        error!("Couldn't allow {src}: {err:?}")
-                    } else if let Err(err) = add_added(
-                        ToAdd {
-                            src_ip: to_check.src_ip,
-                            dst_ip: to_check.dst_ip,
-                        },
-                        pool,
-                    )
-                    .await
-                    {
-                        error!("Error inserting added: {err:?}");
+    let count: i64 = ingested_objects::table
+        .find((&obj.bucket, &obj.key, &obj.etag))
+        .count()
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(count > 0)
+}
+
+pub async fn add_ingested(
+    obj: NewIngestedObject,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<(), Error> {
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(ingested_objects::table)
+        .values(&obj)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Records an object that can never be ingested so it can be looked at later
+pub async fn add_failed_object(
+    failed: NewFailedObject,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<(), Error> {
+    let mut conn = pool.get().await?;
+
+    diesel::insert_into(failed_objects::table)
+        .values(&failed)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Judges the pairs with recent knocks, granting or denying them.
+///
+/// A dry run's blocks were never inserted, so they're judged on top of what the db already has
+/// for the same pairs, the way a replay judges its blocks.
+pub async fn run_checks(
+    pool: &Pool<AsyncPgConnection>,
+    profiles: &[Profile],
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    info!("Run checks");
+    // Without the instances' services their traffic would count as knocks, so no checks at all
+    // is better than checks without them
+    let window = CheckWindow::new(profiles).with_instances(crate::ec2::open_services().await?);
+
+    let to_check = {
+        let mut conn = pool.get().await?;
+        let to_check = load_to_check(&window, &mut conn).await?;
+        match dry_run.map(DryRun::pending) {
+            Some(pending) if !pending.is_empty() => {
+                let store = with_db(pending, &mut conn).await?;
+                with_pending(to_check, &store, &window)
+            }
+            _ => to_check,
+        }
+    };
+
+    check_candidates(to_check, profiles, pool, dry_run, crate::ec2::lookup).await;
+    Ok(())
+}
+
+/// The db's candidates, with those of the pairs in `store` replaced by what it makes of them
+fn with_pending(
+    mut to_check: Vec<(IpNetwork, IpNetwork, Vec<Knock>)>,
+    store: &Store,
+    window: &CheckWindow,
+) -> Vec<(IpNetwork, IpNetwork, Vec<Knock>)> {
+    to_check.retain(|&(src, dst, _)| !store.has(src, dst));
+    to_check.extend(store.candidates(window));
+    to_check
+}
+
+/// The blocks, denies and added rows the db has for the pairs of `pending`, with `pending` on top
+async fn with_db(pending: Vec<NewBlock>, conn: &mut AsyncPgConnection) -> Result<Store, Error> {
+    let pairs: HashSet<(IpNetwork, IpNetwork)> =
+        pending.iter().map(|b| (b.src_ip, b.dst_ip)).collect();
+    let srcs: Vec<IpNetwork> = pairs
+        .iter()
+        .map(|&(src, _)| src)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let mut store = Store::default();
+
+    let known = blocks::table
+        .select((
+            blocks::src_ip,
+            blocks::dst_ip,
+            blocks::proto,
+            blocks::port,
+            blocks::event_ts,
+            blocks::event_end,
+            blocks::src_port,
+        ))
+        .filter(blocks::src_ip.eq_any(&srcs))
+        .order(blocks::id)
+        .load::<NewBlock>(conn)
+        .await?;
+    for block in known {
+        if pairs.contains(&(block.src_ip, block.dst_ip)) {
+            store.add(block);
+        }
+    }
+
+    let denied = denies::table
+        .select((denies::ip, denies::added_on))
+        .filter(denies::ip.eq_any(&srcs))
+        .load::<(IpNetwork, DateTime<Utc>)>(conn)
+        .await?;
+    for (src, at) in denied {
+        store.deny(src, at.timestamp());
+    }
+
+    let let_in = added::table
+        .select((added::src_ip, added::dst_ip))
+        .filter(added::src_ip.eq_any(&srcs))
+        .load::<(IpNetwork, IpNetwork)>(conn)
+        .await?;
+    // The store is never expired; the db's grants are, by `expire_grants`
+    for (src, dst) in let_in {
+        store.let_in(src, dst, i64::MAX);
+    }
+
+    for block in pending {
+        store.add(block);
+    }
+    Ok(store)
+}
+
+/// Grants or denies each pair on its own; one failing doesn't stop the rest.
+///
+/// `lookup` finds the instance a dst belongs to, [`crate::ec2::lookup`] outside of tests.
+async fn check_candidates<F, Fut>(
+    to_check: Vec<(IpNetwork, IpNetwork, Vec<Knock>)>,
+    profiles: &[Profile],
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+    lookup: F,
+) where
+    F: Fn(IpNetwork) -> Fut,
+    Fut: Future<Output = Result<Option<InstanceInfo>, Error>>,
+{
+    for (src, dst, knocks) in to_check {
+        let now = event_time(&knocks);
+
+        match judge(profiles, dst, &knocks, now, CONFIG.order) {
+            Verdict::Ignore | Verdict::Wait => (),
+            Verdict::Grant(profile) => match lookup(dst).await {
+                Ok(Some(info)) => {
+                    let services = profile.open.as_deref().unwrap_or(info.services());
+                    let backend = info.backend();
+
+                    if let Some(dry_run) = dry_run {
+                        info!("Would allow {src} with profile {}", profile.name);
+                        dry_run.allow(ReportAllow {
+                            src_ip: src,
+                            dst_ip: dst,
+                            profile: profile.name.clone(),
+                            instance: info.name().to_string(),
+                            backend: backend.kind().to_string(),
+                            services: services.to_vec(),
+                            expires_at: Utc::now() + info.ttl(),
+                        });
+                        continue;
+                    }
+
+                    match backend.get().grant(src, &info, services).await {
+                        Err(err) => error!("Couldn't allow {src}: {err:?}"),
+                        Ok(rules) => {
+                            info!("{src} matched profile {}", profile.name);
+                            if let Err(err) = add_added(
+                                ToAdd {
+                                    src_ip: src,
+                                    dst_ip: dst,
+                                    profile: profile.name.clone(),
+                                },
+                                backend,
+                                &rules,
+                                Utc::now() + info.ttl(),
+                                pool,
+                            )
+                            .await
+                            {
+                                // Unrecorded rules would never expire, so take them back out
+                                error!("Error inserting added, revoking {rules:?}: {err:?}");
+                                if let Err(err) = revoke_unrecorded(backend, &rules, pool).await {
+                                    error!("Couldn't revoke unrecorded {rules:?}: {err:?}");
+                                }
+                            }
+                        }
                     }
                 }
-                None => {
-                    let keys: Vec<IpNetwork> =
-                        crate::ec2::get_ip_map().await.keys().copied().collect();
-                    warn!("Unknown dst ip {} in {keys:?}", to_check.dst_ip);
-                    let src = to_check.src_ip;
-                    if let Err(err) = add_deny(to_check, pool).await {
-                        error!("Couldn't insert {src} into the block db: {err:?}")
-                    };
+                // A correct knock on an address we can't place isn't the knocker's fault; the
+                // blocks are left to be checked again on the next run
+                Ok(None) => warn!("{src} matched but dst ip {dst} is unknown"),
+                Err(err) => error!("Couldn't look up dst ip {dst}: {err:?}"),
+            },
+            Verdict::Deny => {
+                if let Err(err) = add_deny(src, dst, pool, dry_run).await {
+                    error!("Couldn't insert {src} into the block db: {err:?}")
                 }
-            };
-        } else if crate::WANTED_CONNS.should_block(conns) {
-            if let Err(err) = add_deny(to_check, pool).await {
-                error!("Couldn't insert {src} into the block db: {err:?}")
             }
         };
     }
-    Ok(())
 }
 
-pub async fn add_added(to_add: ToAdd, pool: &Pool<AsyncPgConnection>) -> Result<(), Error> {
+async fn load_to_check(
+    window: &CheckWindow,
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<(IpNetwork, IpNetwork, Vec<Knock>)>, Error> {
+    let excluded: Vec<String> = window
+        .excluded
+        .iter()
+        .map(|(proto, port)| format!("{}/{port}", proto.name()))
+        .collect();
+    let excluded_on: Vec<String> = window
+        .excluded_on
+        .iter()
+        .map(|&(ip, service)| excluded_on(ip, service))
+        .collect();
+
+    diesel::sql_query(TO_CHECK)
+        .bind::<BigInt, _>(window.secs)
+        .bind::<Array<Text>, _>(excluded)
+        .bind::<BigInt, _>(window.min_knocks as i64)
+        .bind::<BigInt, _>(window.max_knocks as i64)
+        .bind::<Array<Text>, _>(excluded_on)
+        .load::<ToCheck>(conn)
+        .await?
+        .into_iter()
+        .map(|row| {
+            let knocks = serde_json::from_str::<Vec<Knock>>(&row.conns).map_err(Error::db)?;
+            Ok((row.src_ip, row.dst_ip, knocks))
+        })
+        .collect()
+}
+
+/// A service on an address the way the check query spells a block's: `HOST(b.dst_ip)` is the bare
+/// address, and postgres shortens v6 ones the same way `Ipv6Addr` displays them (RFC 5952)
+fn excluded_on(ip: IpAddr, (proto, port): (InetProto, u16)) -> String {
+    format!("{ip}/{}/{port}", proto.name())
+}
+
+/// Records a pair as let in along with the rules that did it, all or nothing
+pub async fn add_added(
+    to_add: ToAdd,
+    backend: Backend,
+    rules: &[AddedRule],
+    expires_at: DateTime<Utc>,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<(), Error> {
     let mut conn = pool.get().await?;
 
-    diesel::insert_into(added::table)
-        .values(&to_add)
-        .execute(&mut conn)
+    let grants: Vec<NewGrant> = rules
+        .iter()
+        .map(|rule| NewGrant {
+            src_ip: to_add.src_ip,
+            dst_ip: to_add.dst_ip,
+            group_id: rule.group_id.clone(),
+            rule_id: rule.rule_id.clone(),
+            expires_at,
+            kind: backend.kind().to_string(),
+        })
+        .collect();
+
+    conn.transaction::<_, Error, _>(|conn| {
+        async move {
+            diesel::insert_into(added::table)
+                .values(&to_add)
+                .execute(conn)
+                .await?;
+
+            if !grants.is_empty() {
+                diesel::insert_into(grants::table)
+                    .values(&grants)
+                    .execute(conn)
+                    .await?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Revokes every grant past its ttl.
+///
+/// Once all of the rules for a src/dst pair are gone its added row and knocks are removed so it
+/// has to knock again to get back in.
+pub async fn expire_grants(pool: &Pool<AsyncPgConnection>) -> Result<(), Error> {
+    info!("Expiring grants");
+    let expired = grants::table
+        .filter(grants::expires_at.le(Utc::now()))
+        .load::<Grant>(&mut pool.get().await?)
         .await?;
 
+    revoke_grants(expired, pool).await?;
+    Ok(())
+}
+
+pub async fn list_grants(pool: &Pool<AsyncPgConnection>) -> Result<Vec<Grant>, Error> {
+    let mut conn = pool.get().await?;
+
+    Ok(grants::table
+        .order(grants::expires_at)
+        .load::<Grant>(&mut conn)
+        .await?)
+}
+
+/// The grants a backend holds in `group_id`
+pub async fn grants_in(
+    backend: Backend,
+    group_id: &str,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<Vec<Grant>, Error> {
+    let mut conn = pool.get().await?;
+
+    Ok(grants::table
+        .filter(grants::kind.eq(backend.kind()))
+        .filter(grants::group_id.eq(group_id))
+        .order(grants::expires_at)
+        .load::<Grant>(&mut conn)
+        .await?)
+}
+
+/// Revokes a grant ahead of its expiry, returning false if there's no grant with the id
+pub async fn revoke_grant(id: i64, pool: &Pool<AsyncPgConnection>) -> Result<bool, Error> {
+    let grant = grants::table
+        .find(id)
+        .first::<Grant>(&mut pool.get().await?)
+        .await
+        .optional()?;
+
+    match grant {
+        None => Ok(false),
+        Some(grant) => Ok(revoke_grants(vec![grant], pool).await? == 1),
+    }
+}
+
+/// Revokes the grants from their backends and deletes them, along with the added row and blocks
+/// of any pair left without grants. Returns how many were revoked.
+async fn revoke_grants(grants: Vec<Grant>, pool: &Pool<AsyncPgConnection>) -> Result<usize, Error> {
+    let mut conn = pool.get().await?;
+    let mut revoked_count = 0;
+
+    let mut pairs = HashSet::new();
+    for grant in grants {
+        let rule = AddedRule {
+            group_id: grant.group_id.clone(),
+            rule_id: grant.rule_id.clone(),
+        };
+        let shared = shared_by(&grant.kind, &rule, Some(grant.id), &mut conn).await?;
+
+        let revoked = match grant.kind.parse::<Backend>() {
+            _ if shared > 0 => Ok(()),
+            Ok(backend) => backend.get().revoke(&rule).await,
+            Err(err) => Err(Error::grant(err)),
+        };
+
+        match revoked {
+            Err(err) => error!(
+                "Couldn't revoke {} from {} for {}: {err:?}",
+                grant.rule_id, grant.group_id, grant.src_ip
+            ),
+            Ok(_) => {
+                diesel::delete(grants::table.find(grant.id))
+                    .execute(&mut conn)
+                    .await?;
+                pairs.insert((grant.src_ip, grant.dst_ip));
+                revoked_count += 1;
+            }
+        }
+    }
+
+    for (src_ip, dst_ip) in pairs {
+        let remaining: i64 = grants::table
+            .filter(grants::src_ip.eq(src_ip))
+            .filter(grants::dst_ip.eq(dst_ip))
+            .count()
+            .get_result(&mut conn)
+            .await?;
+
+        if remaining == 0 {
+            diesel::delete(
+                blocks::table
+                    .filter(blocks::src_ip.eq(src_ip))
+                    .filter(blocks::dst_ip.eq(dst_ip)),
+            )
+            .execute(&mut conn)
+            .await?;
+            diesel::delete(added::table.find((src_ip, dst_ip)))
+                .execute(&mut conn)
+                .await?;
+            info!("Expired {src_ip} to {dst_ip}");
+        }
+    }
+
+    Ok(revoked_count)
+}
+
+/// Takes back the rules of a grant that couldn't be recorded, leaving those recorded by other
+/// grants (a prefix list entry or nft element the src already had) for their own expiry
+async fn revoke_unrecorded(
+    backend: Backend,
+    rules: &[AddedRule],
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<(), Error> {
+    let mut conn = pool.get().await?;
+
+    for rule in rules {
+        if shared_by(backend.kind(), rule, None, &mut conn).await? > 0 {
+            info!("Leaving {rule:?}, other grants use it");
+            continue;
+        }
+        if let Err(err) = backend.get().revoke(rule).await {
+            error!("Couldn't revoke unrecorded {rule:?}: {err:?}");
+        }
+    }
+
     Ok(())
 }
 
-pub async fn add_deny(check: ViewToCheck, pool: &Pool<AsyncPgConnection>) -> Result<(), Error> {
+/// How many grants other than `except` hold the rule. A prefix list entry stays until the last
+/// grant using it has expired (and an nft element until the last one for the same host and
+/// service).
+async fn shared_by(
+    kind: &str,
+    rule: &AddedRule,
+    except: Option<i64>,
+    conn: &mut AsyncPgConnection,
+) -> Result<i64, Error> {
+    let mut query = grants::table
+        .filter(grants::kind.eq(kind))
+        .filter(grants::group_id.eq(&rule.group_id))
+        .filter(grants::rule_id.eq(&rule.rule_id))
+        .into_boxed();
+    if let Some(id) = except {
+        query = query.filter(grants::id.ne(id));
+    }
+
+    Ok(query.count().get_result(conn).await?)
+}
+
+pub async fn add_deny(
+    src_ip: IpNetwork,
+    dst_ip: IpNetwork,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    if let Some(dry_run) = dry_run {
+        info!("Would deny {src_ip}");
+        dry_run.deny(src_ip, dst_ip);
+        return Ok(());
+    }
+
     let mut conn = pool.get().await?;
 
     diesel::insert_into(denies::table)
-        .values(denies::ip.eq(check.src_ip))
+        .values(denies::ip.eq(src_ip))
         .execute(&mut conn)
         .await?;
 
@@ -143,19 +623,32 @@ pub async fn insert_test_data(pool: &Pool<AsyncPgConnection>) -> Result<(), Erro
     let localip = IpNetwork::from_str("127.0.0.1").unwrap();
     let otherip1 = IpNetwork::from_str("10.123.21.1").unwrap();
     let otherip2 = IpNetwork::from_str("172.16.5.4").unwrap();
+    let localip6 = IpNetwork::from_str("::1").unwrap();
+    let otherip6 = IpNetwork::from_str("2001:db8::21:1").unwrap();
 
     let dstip = IpNetwork::from_str("10.99.88.44").unwrap();
+    let dstip6 = IpNetwork::from_str("2001:db8::88:44").unwrap();
 
     let mut new_block = NewBlock {
         src_ip: localip,
         dst_ip: dstip,
         event_ts: Utc::now(),
+        event_end: Utc::now(),
+        src_port: None,
         proto: Tcp,
         port: 55,
     };
 
-    for ip in [localip, otherip1, otherip2] {
+    // v6 sources knock on the v6 dst the same way the v4 ones do on the v4 dst
+    for (ip, dst) in [
+        (localip, dstip),
+        (otherip1, dstip),
+        (otherip2, dstip),
+        (localip6, dstip6),
+        (otherip6, dstip6),
+    ] {
         new_block.src_ip = ip;
+        new_block.dst_ip = dst;
 
         for proto in [Tcp, Udp] {
             new_block.proto = proto;
@@ -186,5 +679,226 @@ pub async fn insert_test_data(pool: &Pool<AsyncPgConnection>) -> Result<(), Erro
         .await
         .expect("Bad insert");
 
+    diesel::insert_into(denies::table)
+        .values(denies::ip.eq(localip6))
+        .execute(&mut conn)
+        .await
+        .expect("Bad insert");
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::dry_run::Report;
+    use crate::models::Conns;
+    use crate::models::InetProto::*;
+
+    const SRC: &str = "73.5.159.5";
+    const DST: &str = "10.0.0.7";
+    const START: i64 = 1_682_484_986;
+
+    fn net(ip: &str) -> IpNetwork {
+        ip.parse().unwrap()
+    }
+
+    fn profiles() -> Vec<Profile> {
+        vec![Profile::from_conns(Conns(vec![
+            (Tcp, 7614),
+            (Udp, 1234),
+            (Tcp, 9971),
+        ]))]
+    }
+
+    /// The profile's sequence ten seconds apart, each from its own source port
+    fn sequence(start: i64) -> Vec<NewBlock> {
+        knocked(SRC, DST, &[(Tcp, 7614), (Udp, 1234), (Tcp, 9971)], start)
+    }
+
+    fn knocked(src: &str, dst: &str, conns: &[(InetProto, u16)], start: i64) -> Vec<NewBlock> {
+        conns
+            .iter()
+            .zip(0..)
+            .map(|(&(proto, port), idx)| {
+                let ts = start + idx * 10;
+                NewBlock {
+                    src_ip: net(src),
+                    dst_ip: net(dst),
+                    proto,
+                    port: i32::from(port),
+                    event_ts: Utc.timestamp_opt(ts, 0).unwrap(),
+                    event_end: Utc.timestamp_opt(ts + 10, 0).unwrap(),
+                    src_port: Some(40_000 + idx as i32),
+                }
+            })
+            .collect()
+    }
+
+    fn knocks(blocks: &[NewBlock]) -> Vec<Knock> {
+        blocks
+            .iter()
+            .map(|b| Knock {
+                proto: b.proto,
+                port: b.port as u16,
+                ts: b.event_ts.timestamp(),
+                end: b.event_end.timestamp(),
+            })
+            .collect()
+    }
+
+    async fn lookup(_dst: IpNetwork) -> Result<Option<InstanceInfo>, Error> {
+        Ok(Some(InstanceInfo::with_services("knocked", &[(Tcp, 22)])))
+    }
+
+    /// A dry run's checks over `to_check` and `store`; the pool is never connected to
+    async fn dry_checked(
+        to_check: Vec<(IpNetwork, IpNetwork, Vec<Knock>)>,
+        store: &Store,
+        dry_run: DryRun,
+    ) -> Report {
+        let profiles = profiles();
+        let window = CheckWindow::new(&profiles);
+        let pool = Pool::builder(AsyncDieselConnectionManager::new(
+            "postgres://localhost/none",
+        ))
+        .build()
+        .unwrap();
+
+        let to_check = with_pending(to_check, store, &window);
+        check_candidates(to_check, &profiles, &pool, Some(&dry_run), lookup).await;
+        dry_run.into_report()
+    }
+
+    #[tokio::test]
+    async fn dry_run_blocks_are_allowed() {
+        let dry_run = DryRun::default();
+        dry_run.blocks(&sequence(START));
+
+        let mut store = Store::default();
+        for block in dry_run.pending() {
+            store.add(block);
+        }
+
+        let report = dry_checked(Vec::new(), &store, dry_run).await;
+        let allowed: Vec<_> = report
+            .allowed
+            .iter()
+            .map(|a| (a.src_ip, a.dst_ip, a.instance.as_str()))
+            .collect();
+        assert_eq!(allowed, [(net(SRC), net(DST), "knocked")]);
+        assert!(report.denied.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_blocks_finish_what_the_db_started() {
+        let mut blocks = sequence(START);
+        let pending = blocks.split_off(1);
+
+        // The db's candidate for the pair only has the first knock
+        let to_check = vec![(net(SRC), net(DST), knocks(&blocks))];
+        let mut store = Store::default();
+        for block in blocks.into_iter().chain(pending) {
+            store.add(block);
+        }
+
+        let report = dry_checked(to_check, &store, DryRun::default()).await;
+        assert_eq!(report.allowed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn denied_srcs_are_not_judged_again() {
+        let mut store = Store::default();
+        store.deny(net(SRC), START);
+        for block in sequence(START) {
+            store.add(block);
+        }
+
+        let report = dry_checked(Vec::new(), &store, DryRun::default()).await;
+        assert!(report.allowed.is_empty());
+        assert!(report.denied.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v4_and_v6_pairs_are_checked_alike() {
+        let wanted = [(Tcp, 7614), (Udp, 1234), (Tcp, 9971)];
+        let wrong = [(Tcp, 7615), (Udp, 1235), (Tcp, 9972)];
+
+        let mut store = Store::default();
+        for (src, dst, conns) in [
+            (SRC, DST, wanted),
+            ("2001:db8::1", "2001:db8::7", wanted),
+            ("73.5.159.6", DST, wrong),
+            ("2001:db8::2", "2001:db8::7", wrong),
+        ] {
+            for block in knocked(src, dst, &conns, START) {
+                store.add(block);
+            }
+        }
+
+        let report = dry_checked(Vec::new(), &store, DryRun::default()).await;
+        let mut allowed: Vec<_> = report
+            .allowed
+            .iter()
+            .map(|a| (a.src_ip, a.dst_ip))
+            .collect();
+        let mut denied: Vec<_> = report.denied.iter().map(|d| (d.src_ip, d.dst_ip)).collect();
+        allowed.sort();
+        denied.sort();
+
+        assert_eq!(
+            allowed,
+            [
+                (net(SRC), net(DST)),
+                (net("2001:db8::1"), net("2001:db8::7"))
+            ]
+        );
+        assert_eq!(
+            denied,
+            [
+                (net("73.5.159.6"), net(DST)),
+                (net("2001:db8::2"), net("2001:db8::7"))
+            ]
+        );
+    }
+
+    #[test]
+    fn excluded_on_is_spelled_like_the_query() {
+        // What `HOST(b.dst_ip) || '/' || b.proto::TEXT || '/' || b.port` gives for each
+        for (ip, service, spelled) in [
+            ("10.0.0.7", (Tcp, 2222), "10.0.0.7/tcp/2222"),
+            ("2001:db8::7", (Tcp, 2222), "2001:db8::7/tcp/2222"),
+            ("2001:DB8:0:0:0:0:0:7", (Udp, 53), "2001:db8::7/udp/53"),
+            (
+                "2001:db8:0:1:1:1:1:1",
+                (Tcp, 22),
+                "2001:db8:0:1:1:1:1:1/tcp/22",
+            ),
+            (
+                "2001:db8:0:0:1:0:0:1",
+                (Tcp, 22),
+                "2001:db8::1:0:0:1/tcp/22",
+            ),
+            ("::ffff:10.0.0.7", (Tcp, 22), "::ffff:10.0.0.7/tcp/22"),
+        ] {
+            assert_eq!(excluded_on(ip.parse().unwrap(), service), spelled);
+        }
+    }
+
+    #[test]
+    fn redelivered_dry_run_blocks_are_deduped() {
+        let profiles = profiles();
+        let window = CheckWindow::new(&profiles);
+
+        let mut store = Store::default();
+        for block in sequence(START).into_iter().chain(sequence(START)) {
+            store.add(block);
+        }
+
+        let candidates = with_pending(Vec::new(), &store, &window);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].2, knocks(&sequence(START)));
+    }
+}
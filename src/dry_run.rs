@@ -0,0 +1,100 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+
+use crate::models::{InetProto, NewBlock};
+
+/// Collects what a run would have done instead of doing it.
+///
+/// Nothing is written to the db or any grant backend while one is in use; reads still happen so
+/// the checks see the real state.
+#[derive(Default)]
+pub struct DryRun {
+    report: Mutex<Report>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub blocks: Vec<ReportBlock>,
+    pub allowed: Vec<ReportAllow>,
+    pub denied: Vec<ReportDeny>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportBlock {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub proto: InetProto,
+    pub src_port: Option<i32>,
+    pub port: i32,
+    pub event_ts: DateTime<Utc>,
+    pub event_end: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportAllow {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub profile: String,
+    pub instance: String,
+    pub backend: String,
+    pub services: Vec<(InetProto, u16)>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportDeny {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+}
+
+impl DryRun {
+    pub fn blocks(&self, blocks: &[NewBlock]) {
+        self.report()
+            .blocks
+            .extend(blocks.iter().map(|b| ReportBlock {
+                src_ip: b.src_ip,
+                dst_ip: b.dst_ip,
+                proto: b.proto,
+                src_port: b.src_port,
+                port: b.port,
+                event_ts: b.event_ts,
+                event_end: b.event_end,
+            }));
+    }
+
+    /// The blocks that would have been inserted, to be checked as if they had been
+    pub fn pending(&self) -> Vec<NewBlock> {
+        self.report()
+            .blocks
+            .iter()
+            .map(|b| NewBlock {
+                src_ip: b.src_ip,
+                dst_ip: b.dst_ip,
+                proto: b.proto,
+                port: b.port,
+                event_ts: b.event_ts,
+                event_end: b.event_end,
+                src_port: b.src_port,
+            })
+            .collect()
+    }
+
+    pub fn allow(&self, allow: ReportAllow) {
+        self.report().allowed.push(allow);
+    }
+
+    pub fn deny(&self, src_ip: IpNetwork, dst_ip: IpNetwork) {
+        self.report().denied.push(ReportDeny { src_ip, dst_ip });
+    }
+
+    pub fn into_report(self) -> Report {
+        self.report.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn report(&self) -> std::sync::MutexGuard<'_, Report> {
+        self.report.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
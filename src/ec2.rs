@@ -1,20 +1,141 @@
 use std::collections::HashMap;
+use std::pin::pin;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::types::{
+    AddPrefixListEntry, Filter, Instance, IpPermission, IpRange, Ipv6Range, PortRange,
+    PrefixListEntry, RemovePrefixListEntry, ResourceType, RuleAction, Tag, TagSpecification,
+};
 use aws_sdk_ec2::Client;
+use futures_util::TryStreamExt;
 use ipnetwork::IpNetwork;
-use lambda_runtime::Error;
-use tokio::sync::OnceCell;
-use tracing::log::{error, info};
+use once_cell::sync::Lazy;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::log::{error, info, warn};
 
 use crate::aws::get_conf;
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::grant::{AddedRule, Backend, Listed};
+use crate::models::InetProto;
 
+const TTL_TAG: &str = "pknocker:ttl";
+const OPEN_TAG: &str = "pknocker:open";
+/// Overrides the configured grant backend for the instance
+const BACKEND_TAG: &str = "pknocker:backend";
+/// The instance's own managed prefix lists for v4 and v6 knockers, referenced only by its groups
+const PREFIX_LIST_TAG: &str = "pknocker:prefix-list";
+const PREFIX_LIST_V6_TAG: &str = "pknocker:prefix-list-v6";
+/// Marks a security group as the knock group of the instance id in its value
+const KNOCK_GROUP_TAG: &str = "pknocker:knock-group";
+const KNOCK_GROUP_PREFIX: &str = "pknocker-knock-";
+
+/// What's opened when an instance has no `pknocker:open` tag
+pub const DEFAULT_SERVICES: [(InetProto, u16); 1] = [(InetProto::Tcp, 22)];
+
+/// Attempts at a prefix list change before giving up; each one can lose a race with another
+/// writer bumping the list's version
+const PREFIX_LIST_ATTEMPTS: u64 = 5;
+
+/// Marks what pknocker creates, so it can be told apart when listing
+const DESCRIPTION: &str = "pknocker";
+
+/// Which of an instance's security groups get the rules opened to a knocker
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GrantGroups {
+    /// Every group on the instance (the original behaviour), even ones shared with other hosts
+    Shared,
+    /// Only the instance's own knock group, named `pknocker-knock-<instance id>` or tagged
+    /// `pknocker:knock-group=<instance id>`; `create` makes and attaches one when there isn't any
+    Dedicated { create: bool },
+}
+
+#[derive(Clone)]
 pub struct InstanceInfo {
     name: String,
+    instance_id: String,
+    vpc_id: Option<String>,
+    subnet_id: Option<String>,
     idents: Vec<String>,
+    /// The attached group following the knock group naming convention, if any
+    knock_group: Option<String>,
+    ttl_secs: i64,
+    services: Vec<(InetProto, u16)>,
+    backend: Backend,
+    prefix_list_v4: Option<String>,
+    prefix_list_v6: Option<String>,
+}
+
+impl InstanceInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// How knockers are let in to this instance
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// What's opened to a knocker unless their profile says otherwise
+    pub fn services(&self) -> &[(InetProto, u16)] {
+        &self.services
+    }
+
+    /// The prefix list a knocker from `src` is added to: the instance's tagged one, else the
+    /// configured one (which opens every group referencing it, not just this instance's)
+    pub fn prefix_list(&self, src: IpNetwork) -> Option<&str> {
+        match src {
+            IpNetwork::V4(_) => self
+                .prefix_list_v4
+                .as_ref()
+                .or(CONFIG.prefix_list_v4.as_ref()),
+            IpNetwork::V6(_) => self
+                .prefix_list_v6
+                .as_ref()
+                .or(CONFIG.prefix_list_v6.as_ref()),
+        }
+        .map(String::as_str)
+    }
+
+    /// How long rules opened to this instance stay open
+    pub fn ttl(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.ttl_secs)
+    }
 }
 
-static IP_MAP: OnceCell<HashMap<IpNetwork, InstanceInfo>> = OnceCell::const_new();
+#[cfg(test)]
+impl InstanceInfo {
+    /// An instance let in to by group rules, for tests of what's done with a match
+    pub fn with_services(name: &str, services: &[(InetProto, u16)]) -> InstanceInfo {
+        InstanceInfo {
+            name: name.to_string(),
+            instance_id: format!("i-{name}"),
+            vpc_id: None,
+            subnet_id: None,
+            idents: Vec::new(),
+            knock_group: None,
+            ttl_secs: 3600,
+            services: services.to_vec(),
+            backend: Backend::SgRules,
+            prefix_list_v4: None,
+            prefix_list_v6: None,
+        }
+    }
+}
+
+/// Instances by each of their addresses, refreshed once it's older than the configured TTL
+struct IpMap {
+    fetched: Instant,
+    instances: HashMap<IpNetwork, InstanceInfo>,
+}
+
+static IP_MAP: Lazy<Mutex<Option<IpMap>>> = Lazy::new(|| Mutex::new(None));
 
 static CLIENT: OnceCell<Client> = OnceCell::const_new();
 
@@ -24,64 +145,873 @@ async fn get_client<'a>() -> &'a Client {
         .await
 }
 
-pub async fn get_ip_map<'a>() -> &'a HashMap<IpNetwork, InstanceInfo> {
-    IP_MAP
-        .get_or_init(|| async {
-            let client = get_client().await;
-            let instances = client.describe_instances().send().await.unwrap();
-
-            let mut map = HashMap::new();
-
-            for res in instances.reservations().unwrap_or_default() {
-                for instance in res.instances().unwrap_or_default() {
-                    let name = instance.key_name().unwrap_or_default().to_string();
-                    for ips in [instance.public_ip_address(), instance.private_ip_address()] {
-                        if let Ok(ip) = IpNetwork::from_str(ips.unwrap_or_default()) {
-                            let groups = instance.security_groups().unwrap_or_default();
-                            if !groups.is_empty() {
-                                let idents = groups
-                                    .iter()
-                                    .flat_map(|g| g.group_id().map(|s| s.to_string()))
-                                    .collect();
-                                map.insert(
-                                    ip,
-                                    InstanceInfo {
-                                        name: name.clone(),
-                                        idents,
-                                    },
-                                );
-                            }
-                        }
-                    }
+/// The instance behind `ip`.
+///
+/// The whole map is reloaded once it's stale, and an ip that isn't in it is looked up on its own
+/// in case the instance was launched (or readdressed) since the last load.
+pub async fn lookup(ip: IpNetwork) -> Result<Option<InstanceInfo>, Error> {
+    let mut map = IP_MAP.lock().await;
+    let map = fresh(&mut map).await?;
+
+    if let Some(info) = map.instances.get(&ip) {
+        return Ok(Some(info.clone()));
+    }
+
+    for filter in filters_for(ip) {
+        let found = describe(vec![filter]).await?;
+        if let Some(info) = found.get(&ip).cloned() {
+            info!("Found {} for {ip} after a miss", info.name);
+            map.instances.extend(found);
+            return Ok(Some(info));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Filters matching instances with `ip`; a v4 address could be either the public or private one
+fn filters_for(ip: IpNetwork) -> Vec<Filter> {
+    let names: &[&str] = match ip {
+        IpNetwork::V4(_) => &[
+            "ip-address",
+            "network-interface.addresses.private-ip-address",
+        ],
+        IpNetwork::V6(_) => &["network-interface.ipv6-addresses.ipv6-address"],
+    };
+
+    names
+        .iter()
+        .map(|name| {
+            Filter::builder()
+                .name(*name)
+                .values(ip.ip().to_string())
+                .build()
+        })
+        .collect()
+}
+
+/// Every enrolled instance matching `filters`, by each of its addresses
+async fn describe(mut filters: Vec<Filter>) -> Result<HashMap<IpNetwork, InstanceInfo>, Error> {
+    if let Some((key, value)) = &CONFIG.enroll_tag {
+        filters.push(
+            Filter::builder()
+                .name(format!("tag:{key}"))
+                .values(value)
+                .build(),
+        );
+    }
+
+    let client = get_client().await;
+    let mut reservations = pin!(client
+        .describe_instances()
+        .set_filters(Some(filters))
+        .into_paginator()
+        .items()
+        .send());
+
+    let mut map = HashMap::new();
+
+    while let Some(res) = reservations.try_next().await.map_err(Error::ec2)? {
+        for instance in res.instances().unwrap_or_default() {
+            if let Some(info) = instance_info(instance) {
+                for ip in addresses(instance) {
+                    map.insert(ip, info.clone());
                 }
             }
+        }
+    }
+
+    Ok(map)
+}
+
+/// Instances without security groups have nothing to open rules on
+fn instance_info(instance: &Instance) -> Option<InstanceInfo> {
+    let groups = instance.security_groups().unwrap_or_default();
+    if groups.is_empty() {
+        return None;
+    }
+
+    let name = instance.key_name().unwrap_or_default().to_string();
+    let instance_id = instance.instance_id().unwrap_or_default().to_string();
+    let knock_group_name = format!("{KNOCK_GROUP_PREFIX}{instance_id}");
+
+    Some(InstanceInfo {
+        idents: groups
+            .iter()
+            .flat_map(|g| g.group_id().map(|s| s.to_string()))
+            .collect(),
+        knock_group: groups
+            .iter()
+            .find(|g| g.group_name() == Some(knock_group_name.as_str()))
+            .and_then(|g| g.group_id().map(|s| s.to_string())),
+        vpc_id: instance.vpc_id().map(|s| s.to_string()),
+        subnet_id: instance.subnet_id().map(|s| s.to_string()),
+        backend: backend(instance, &name),
+        instance_id,
+        ttl_secs: ttl_secs(instance, &name),
+        services: services(instance, &name),
+        prefix_list_v4: tag(instance, PREFIX_LIST_TAG).map(str::to_string),
+        prefix_list_v6: tag(instance, PREFIX_LIST_V6_TAG).map(str::to_string),
+        name,
+    })
+}
+
+/// What each enrolled instance's grants open, by address
+pub async fn open_services() -> Result<Vec<(IpNetwork, (InetProto, u16))>, Error> {
+    let mut map = IP_MAP.lock().await;
+    let map = fresh(&mut map).await?;
+
+    Ok(map
+        .instances
+        .iter()
+        .flat_map(|(ip, info)| info.services.iter().map(|service| (*ip, *service)))
+        .collect())
+}
+
+/// The cached map, loaded again if it's stale. A stale map is only replaced by one that loaded;
+/// if describing fails (throttled, say) the instances already known are better than none.
+async fn fresh(map: &mut Option<IpMap>) -> Result<&mut IpMap, Error> {
+    let ttl = Duration::from_secs(CONFIG.instance_cache_secs);
+
+    if map
+        .as_ref()
+        .is_none_or(|cached| cached.fetched.elapsed() >= ttl)
+    {
+        info!("Loading instances");
+        match describe(Vec::new()).await {
+            Ok(instances) => {
+                *map = Some(IpMap {
+                    fetched: Instant::now(),
+                    instances,
+                })
+            }
+            Err(e) if map.is_some() => {
+                warn!("Couldn't reload instances, keeping the stale ones :: {e}")
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    map.as_mut()
+        .ok_or_else(|| Error::ec2("no instances loaded"))
+}
+
+/// Every v4 and v6 address the instance can be knocked on
+fn addresses(instance: &Instance) -> Vec<IpNetwork> {
+    let v6 = instance
+        .network_interfaces()
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|eni| eni.ipv6_addresses().unwrap_or_default())
+        .map(|addr| addr.ipv6_address());
+
+    let mut ips: Vec<IpNetwork> = [
+        instance.public_ip_address(),
+        instance.private_ip_address(),
+        instance.ipv6_address(),
+    ]
+    .into_iter()
+    .chain(v6)
+    .flat_map(|ip| IpNetwork::from_str(ip.unwrap_or_default()))
+    .collect();
+
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
+fn tag<'a>(instance: &'a Instance, key: &str) -> Option<&'a str> {
+    instance
+        .tags()
+        .unwrap_or_default()
+        .iter()
+        .find(|t| t.key() == Some(key))
+        .and_then(|t| t.value())
+}
 
-            map
+fn ttl_secs(instance: &Instance, name: &str) -> i64 {
+    let tag = tag(instance, TTL_TAG);
+
+    match tag.map(str::parse::<i64>) {
+        None => CONFIG.grant_ttl_secs,
+        Some(Ok(secs)) if secs > 0 => secs,
+        Some(_) => {
+            warn!("Invalid {TTL_TAG} tag for {name}: {tag:?}");
+            CONFIG.grant_ttl_secs
+        }
+    }
+}
+
+fn backend(instance: &Instance, name: &str) -> Backend {
+    match tag(instance, BACKEND_TAG).map(str::parse) {
+        None => CONFIG.grant_backend,
+        Some(Ok(backend)) => backend,
+        Some(Err(err)) => {
+            warn!("Invalid {BACKEND_TAG} tag for {name}: {err}");
+            CONFIG.grant_backend
+        }
+    }
+}
+
+/// The services to open from the `pknocker:open` tag (eg `tcp/2222,udp/51820`), or ssh if it's
+/// missing or invalid
+fn services(instance: &Instance, name: &str) -> Vec<(InetProto, u16)> {
+    match tag(instance, OPEN_TAG).map(parse_services) {
+        None => DEFAULT_SERVICES.to_vec(),
+        Some(Ok(services)) if !services.is_empty() => services,
+        Some(Ok(_)) => {
+            warn!("Empty {OPEN_TAG} tag for {name}");
+            DEFAULT_SERVICES.to_vec()
+        }
+        Some(Err(err)) => {
+            warn!("Invalid {OPEN_TAG} tag for {name}: {err}");
+            DEFAULT_SERVICES.to_vec()
+        }
+    }
+}
+
+fn parse_services(tag: &str) -> Result<Vec<(InetProto, u16)>, String> {
+    tag.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|service| {
+            let (proto, port) = service
+                .split_once('/')
+                .ok_or_else(|| format!("missing '/' in {service:?}"))?;
+
+            let proto = match proto.to_ascii_lowercase().as_str() {
+                "tcp" => InetProto::Tcp,
+                "udp" => InetProto::Udp,
+                _ => return Err(format!("unknown proto in {service:?}")),
+            };
+
+            match port.parse::<u16>() {
+                Ok(port) if port > 0 => Ok((proto, port)),
+                _ => Err(format!("bad port in {service:?}")),
+            }
         })
+        .collect()
+}
+
+/// The groups rules for the instance go on, per the configured [`GrantGroups`]
+async fn grant_groups(info: &InstanceInfo) -> Result<Vec<String>, Error> {
+    let create = match CONFIG.grant_groups {
+        GrantGroups::Shared => return Ok(info.idents.clone()),
+        GrantGroups::Dedicated { create } => create,
+    };
+
+    if let Some(group_id) = &info.knock_group {
+        return Ok(vec![group_id.clone()]);
+    }
+
+    let group_id = match tagged_knock_group(info).await? {
+        Some(group_id) => group_id,
+        None if create => create_knock_group(info).await?,
+        None => {
+            return Err(Error::ec2(format!(
+                "no knock group for {} ({})",
+                info.name, info.instance_id
+            )))
+        }
+    };
+
+    attach_group(info, &group_id).await?;
+    Ok(vec![group_id])
+}
+
+async fn tagged_knock_group(info: &InstanceInfo) -> Result<Option<String>, Error> {
+    let client = get_client().await;
+    let resp = client
+        .describe_security_groups()
+        .filters(
+            Filter::builder()
+                .name(format!("tag:{KNOCK_GROUP_TAG}"))
+                .values(&info.instance_id)
+                .build(),
+        )
+        .send()
         .await
+        .map_err(Error::ec2)?;
+
+    Ok(resp
+        .security_groups()
+        .unwrap_or_default()
+        .iter()
+        .find_map(|g| g.group_id().map(|s| s.to_string())))
 }
 
-pub async fn add_allow(allow_ip: IpNetwork, info: &InstanceInfo) -> Result<(), Error> {
+async fn create_knock_group(info: &InstanceInfo) -> Result<String, Error> {
     let client = get_client().await;
-    let name = info.name.clone();
+    let resp = client
+        .create_security_group()
+        .group_name(format!("{KNOCK_GROUP_PREFIX}{}", info.instance_id))
+        .description(format!("pknocker grants for {}", info.instance_id))
+        .set_vpc_id(info.vpc_id.clone())
+        .tag_specifications(
+            TagSpecification::builder()
+                .resource_type(ResourceType::SecurityGroup)
+                .tags(
+                    Tag::builder()
+                        .key(KNOCK_GROUP_TAG)
+                        .value(&info.instance_id)
+                        .build(),
+                )
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(Error::ec2)?;
 
-    let ip = allow_ip.to_string();
+    let group_id = resp
+        .group_id()
+        .ok_or_else(|| Error::ec2("create_security_group returned no group id"))?;
+    info!("Created knock group {group_id} for {}", info.name);
+    Ok(group_id.to_string())
+}
+
+/// Adds the group to each of the instance's interfaces that doesn't have it yet; the interfaces
+/// are described again since the cached instance info may be out of date
+async fn attach_group(info: &InstanceInfo, group_id: &str) -> Result<(), Error> {
+    let client = get_client().await;
+    let resp = client
+        .describe_network_interfaces()
+        .filters(
+            Filter::builder()
+                .name("attachment.instance-id")
+                .values(&info.instance_id)
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(Error::ec2)?;
+
+    for eni in resp.network_interfaces().unwrap_or_default() {
+        let mut groups: Vec<String> = eni
+            .groups()
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|g| g.group_id().map(|s| s.to_string()))
+            .collect();
+        if groups.iter().any(|g| g == group_id) {
+            continue;
+        }
+        groups.push(group_id.to_string());
 
-    for ident in info.idents.iter() {
-        match client
-            .authorize_security_group_ingress()
-            .set_cidr_ip(Some(ip.clone()))
-            .set_from_port(Some(22))
-            .set_to_port(Some(22))
-            .set_ip_protocol(Some("tcp".to_string()))
-            .set_group_id(Some(ident.clone()))
+        let eni_id = eni.network_interface_id().unwrap_or_default();
+        client
+            .modify_network_interface_attribute()
+            .network_interface_id(eni_id)
+            .set_groups(Some(groups))
             .send()
             .await
-        {
-            Ok(_) => info!("Allow {ip} to {ident} for {name}"),
-            Err(err) => error!("Err allowing {ip} to {ident} for {name}: {err:?}"),
+            .map_err(Error::ec2)?;
+        info!("Attached {group_id} to {eni_id} of {}", info.name);
+    }
+
+    Ok(())
+}
+
+/// Opens `services` to `allow_ip` on the instance's grant groups, returning the rules that were
+/// created
+pub async fn add_rules(
+    allow_ip: IpNetwork,
+    info: &InstanceInfo,
+    services: &[(InetProto, u16)],
+) -> Result<Vec<AddedRule>, Error> {
+    let client = get_client().await;
+    let name = info.name.clone();
+
+    let ip = allow_ip.to_string();
+    let groups = grant_groups(info).await?;
+    let mut rules = Vec::with_capacity(groups.len() * services.len());
+
+    for ident in groups.iter() {
+        for &(proto, port) in services.iter() {
+            let proto = proto.name();
+
+            match client
+                .authorize_security_group_ingress()
+                .ip_permissions(permission(allow_ip, proto, port))
+                .set_group_id(Some(ident.clone()))
+                .send()
+                .await
+            {
+                Ok(resp) => {
+                    info!("Allow {ip} to {proto}/{port} on {ident} for {name}");
+                    rules.extend(
+                        resp.security_group_rules()
+                            .unwrap_or_default()
+                            .iter()
+                            .flat_map(|r| r.security_group_rule_id())
+                            .map(|rule_id| AddedRule {
+                                group_id: ident.clone(),
+                                rule_id: rule_id.to_string(),
+                            }),
+                    );
+                }
+                Err(err) => {
+                    error!("Err allowing {ip} to {proto}/{port} on {ident} for {name}: {err:?}")
+                }
+            };
+        }
+    }
+
+    // Recording a pair with nothing to expire would keep it from being checked again
+    if rules.is_empty() {
+        return Err(Error::ec2(format!(
+            "no rules were added for {ip} on {name}"
+        )));
+    }
+    Ok(rules)
+}
+
+/// A single host rule for the ip; v6 sources have to go in the ipv6 ranges
+fn permission(allow_ip: IpNetwork, proto: &str, port: u16) -> IpPermission {
+    let permission = IpPermission::builder()
+        .ip_protocol(proto)
+        .from_port(port.into())
+        .to_port(port.into());
+
+    match allow_ip {
+        IpNetwork::V4(_) => permission.ip_ranges(
+            IpRange::builder()
+                .cidr_ip(host_cidr(allow_ip))
+                .description(DESCRIPTION)
+                .build(),
+        ),
+        IpNetwork::V6(_) => permission.ipv6_ranges(
+            Ipv6Range::builder()
+                .cidr_ipv6(host_cidr(allow_ip))
+                .description(DESCRIPTION)
+                .build(),
+        ),
+    }
+    .build()
+}
+
+fn host_cidr(ip: IpNetwork) -> String {
+    match ip {
+        IpNetwork::V4(net) => format!("{}/32", net.ip()),
+        IpNetwork::V6(net) => format!("{}/128", net.ip()),
+    }
+}
+
+/// Adds or removes `cidr` from the list.
+///
+/// Changes have to name the list's current version, so the version and entries are read again
+/// whenever another writer gets in first.
+async fn modify_prefix_list(list_id: &str, cidr: &str, add: bool) -> Result<(), Error> {
+    let client = get_client().await;
+
+    for attempt in 1..=PREFIX_LIST_ATTEMPTS {
+        let (version, entries) = prefix_list_entries(list_id).await?;
+        if entries.iter().any(|e| e.cidr() == Some(cidr)) == add {
+            return Ok(());
+        }
+
+        let modify = client
+            .modify_managed_prefix_list()
+            .prefix_list_id(list_id)
+            .current_version(version);
+        let modify = if add {
+            modify.add_entries(
+                AddPrefixListEntry::builder()
+                    .cidr(cidr)
+                    .description(DESCRIPTION)
+                    .build(),
+            )
+        } else {
+            modify.remove_entries(RemovePrefixListEntry::builder().cidr(cidr).build())
         };
+
+        match modify.send().await {
+            Ok(_) => return Ok(()),
+            Err(err)
+                if matches!(
+                    err.code(),
+                    Some("PrefixListVersionMismatch" | "IncorrectState")
+                ) =>
+            {
+                warn!("{list_id} changed under us (attempt {attempt}): {err:?}");
+                tokio::time::sleep(Duration::from_millis(250 * attempt)).await;
+            }
+            Err(err) => return Err(Error::ec2(err)),
+        }
     }
 
+    Err(Error::ec2(format!(
+        "gave up changing {list_id} after {PREFIX_LIST_ATTEMPTS} attempts"
+    )))
+}
+
+async fn prefix_list_entries(list_id: &str) -> Result<(i64, Vec<PrefixListEntry>), Error> {
+    let client = get_client().await;
+    let lists = client
+        .describe_managed_prefix_lists()
+        .prefix_list_ids(list_id)
+        .send()
+        .await
+        .map_err(Error::ec2)?;
+
+    let version = lists
+        .prefix_lists()
+        .unwrap_or_default()
+        .iter()
+        .find_map(|list| list.version())
+        .ok_or_else(|| Error::ec2(format!("prefix list {list_id} not found")))?;
+
+    let entries = client
+        .get_managed_prefix_list_entries()
+        .prefix_list_id(list_id)
+        .target_version(version)
+        .into_paginator()
+        .items()
+        .send()
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(Error::ec2)?;
+
+    Ok((version, entries))
+}
+
+/// Removes a rule created by [`add_rules`]; a rule that's already gone counts as revoked
+pub async fn revoke_rule(group_id: &str, rule_id: &str) -> Result<(), Error> {
+    let client = get_client().await;
+
+    match client
+        .revoke_security_group_ingress()
+        .group_id(group_id)
+        .security_group_rule_ids(rule_id)
+        .send()
+        .await
+    {
+        Ok(_) => {
+            info!("Revoked {rule_id} from {group_id}");
+            Ok(())
+        }
+        Err(err)
+            if matches!(
+                err.code(),
+                Some("InvalidPermission.NotFound" | "InvalidSecurityGroupRuleId.NotFound")
+            ) =>
+        {
+            warn!("Rule {rule_id} was already gone from {group_id}");
+            Ok(())
+        }
+        Err(err) => Err(Error::ec2(err)),
+    }
+}
+
+/// The inbound host rules in the group that [`add_rules`] made
+pub async fn list_rules(group_id: &str) -> Result<Vec<Listed>, Error> {
+    let client = get_client().await;
+    let rules = client
+        .describe_security_group_rules()
+        .filters(Filter::builder().name("group-id").values(group_id).build())
+        .into_paginator()
+        .items()
+        .send()
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(Error::ec2)?;
+
+    Ok(rules
+        .iter()
+        .filter(|r| r.is_egress() == Some(false) && r.description() == Some(DESCRIPTION))
+        .flat_map(|r| {
+            let src = r.cidr_ipv4().or(r.cidr_ipv6())?.parse().ok()?;
+            Some(Listed {
+                rule: AddedRule {
+                    group_id: group_id.to_string(),
+                    rule_id: r.security_group_rule_id()?.to_string(),
+                },
+                src,
+            })
+        })
+        .collect())
+}
+
+pub async fn add_to_prefix_list(
+    list_id: &str,
+    allow_ip: IpNetwork,
+    info: &InstanceInfo,
+) -> Result<AddedRule, Error> {
+    let cidr = host_cidr(allow_ip);
+    modify_prefix_list(list_id, &cidr, true).await?;
+    info!("Allow {allow_ip} in {list_id} for {}", info.name);
+
+    Ok(AddedRule {
+        group_id: list_id.to_string(),
+        rule_id: cidr,
+    })
+}
+
+pub async fn remove_from_prefix_list(list_id: &str, cidr: &str) -> Result<(), Error> {
+    modify_prefix_list(list_id, cidr, false).await?;
+    info!("Revoked {cidr} from {list_id}");
     Ok(())
 }
+
+/// The entries in the list that [`add_to_prefix_list`] made
+pub async fn list_prefix_list(list_id: &str) -> Result<Vec<Listed>, Error> {
+    let (_, entries) = prefix_list_entries(list_id).await?;
+
+    Ok(entries
+        .iter()
+        .filter(|e| e.description() == Some(DESCRIPTION))
+        .flat_map(|e| {
+            let cidr = e.cidr()?;
+            Some(Listed {
+                rule: AddedRule {
+                    group_id: list_id.to_string(),
+                    rule_id: cidr.to_string(),
+                },
+                src: cidr.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+/// Allows `services` from `allow_ip` in the network ACL of the instance's subnet, using the
+/// lowest free rule numbers in the configured range
+pub async fn add_nacl_entries(
+    allow_ip: IpNetwork,
+    info: &InstanceInfo,
+    services: &[(InetProto, u16)],
+) -> Result<Vec<AddedRule>, Error> {
+    let client = get_client().await;
+    let subnet_id = info
+        .subnet_id
+        .as_deref()
+        .ok_or_else(|| Error::ec2(format!("{} isn't in a subnet", info.name)))?;
+
+    let acl = client
+        .describe_network_acls()
+        .filters(
+            Filter::builder()
+                .name("association.subnet-id")
+                .values(subnet_id)
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(Error::ec2)?
+        .network_acls()
+        .unwrap_or_default()
+        .first()
+        .cloned()
+        .ok_or_else(|| Error::ec2(format!("no network acl for {subnet_id}")))?;
+    let acl_id = acl.network_acl_id().unwrap_or_default();
+
+    let mut used: Vec<i32> = acl
+        .entries()
+        .unwrap_or_default()
+        .iter()
+        .filter(|e| e.egress() == Some(false))
+        .flat_map(|e| e.rule_number())
+        .collect();
+    let (first, last) = CONFIG.nacl_rules;
+    let mut entries = Vec::with_capacity(services.len());
+
+    for &(proto, port) in services.iter() {
+        let mut created = false;
+
+        while !created {
+            let number = (first..=last)
+                .find(|n| !used.contains(n))
+                .ok_or_else(|| Error::ec2(format!("no free rule numbers left in {acl_id}")))?;
+            used.push(number);
+
+            let entry = client
+                .create_network_acl_entry()
+                .network_acl_id(acl_id)
+                .rule_number(number)
+                .protocol(proto.number().to_string())
+                .rule_action(RuleAction::Allow)
+                .egress(false)
+                .port_range(
+                    PortRange::builder()
+                        .from(port.into())
+                        .to(port.into())
+                        .build(),
+                );
+            let entry = match allow_ip {
+                IpNetwork::V4(_) => entry.cidr_block(host_cidr(allow_ip)),
+                IpNetwork::V6(_) => entry.ipv6_cidr_block(host_cidr(allow_ip)),
+            };
+
+            match entry.send().await {
+                Ok(_) => {
+                    info!(
+                        "Allow {allow_ip} to {}/{port} in {acl_id} #{number} for {}",
+                        proto.name(),
+                        info.name
+                    );
+                    entries.push(AddedRule {
+                        group_id: acl_id.to_string(),
+                        rule_id: number.to_string(),
+                    });
+                    created = true;
+                }
+                // Someone else took the number since we looked; try the next one
+                Err(err) if err.code() == Some("NetworkAclEntryAlreadyExists") => {
+                    warn!("{acl_id} #{number} was taken")
+                }
+                Err(err) => return Err(Error::ec2(err)),
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Removes an entry made by [`add_nacl_entries`]; the numbers in the range are assumed to be ours
+pub async fn delete_nacl_entry(acl_id: &str, rule_number: &str) -> Result<(), Error> {
+    let client = get_client().await;
+    let number = rule_number
+        .parse::<i32>()
+        .map_err(|_| Error::ec2(format!("bad nacl rule number {rule_number:?}")))?;
+
+    match client
+        .delete_network_acl_entry()
+        .network_acl_id(acl_id)
+        .rule_number(number)
+        .egress(false)
+        .send()
+        .await
+    {
+        Ok(_) => {
+            info!("Revoked #{number} from {acl_id}");
+            Ok(())
+        }
+        Err(err) if err.code() == Some("InvalidNetworkAclEntry.NotFound") => {
+            warn!("Entry #{number} was already gone from {acl_id}");
+            Ok(())
+        }
+        Err(err) => Err(Error::ec2(err)),
+    }
+}
+
+/// The inbound allow entries of the acl in the configured range
+pub async fn list_nacl_entries(acl_id: &str) -> Result<Vec<Listed>, Error> {
+    let client = get_client().await;
+    let resp = client
+        .describe_network_acls()
+        .network_acl_ids(acl_id)
+        .send()
+        .await
+        .map_err(Error::ec2)?;
+    let (first, last) = CONFIG.nacl_rules;
+
+    Ok(resp
+        .network_acls()
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|acl| acl.entries().unwrap_or_default())
+        .filter(|e| e.egress() == Some(false) && e.rule_action() == Some(&RuleAction::Allow))
+        .flat_map(|e| {
+            let number = e.rule_number().filter(|n| (first..=last).contains(n))?;
+            Some(Listed {
+                rule: AddedRule {
+                    group_id: acl_id.to_string(),
+                    rule_id: number.to_string(),
+                },
+                src: e.cidr_block().or(e.ipv6_cidr_block())?.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::types::{InstanceIpv6Address, InstanceNetworkInterface};
+
+    use super::*;
+
+    fn net(ip: &str) -> IpNetwork {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn host_cidr_is_a_single_address() {
+        assert_eq!(host_cidr(net("198.51.100.7")), "198.51.100.7/32");
+        assert_eq!(host_cidr(net("2001:db8::7")), "2001:db8::7/128");
+        // Only ever the host, whatever the network it came in as
+        assert_eq!(host_cidr(net("198.51.100.7/24")), "198.51.100.7/32");
+        assert_eq!(host_cidr(net("2001:db8::7/64")), "2001:db8::7/128");
+    }
+
+    #[test]
+    fn v4_permission_uses_ip_ranges() {
+        let permission = permission(net("198.51.100.7"), "tcp", 22);
+
+        assert_eq!(permission.ip_protocol(), Some("tcp"));
+        assert_eq!(permission.from_port(), Some(22));
+        assert_eq!(permission.to_port(), Some(22));
+        assert!(permission.ipv6_ranges().unwrap_or_default().is_empty());
+
+        let ranges = permission.ip_ranges().unwrap_or_default();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].cidr_ip(), Some("198.51.100.7/32"));
+        assert_eq!(ranges[0].description(), Some(DESCRIPTION));
+    }
+
+    #[test]
+    fn v6_permission_uses_ipv6_ranges() {
+        let permission = permission(net("2001:db8::7"), "udp", 51820);
+
+        assert_eq!(permission.ip_protocol(), Some("udp"));
+        assert_eq!(permission.from_port(), Some(51820));
+        assert_eq!(permission.to_port(), Some(51820));
+        assert!(permission.ip_ranges().unwrap_or_default().is_empty());
+
+        let ranges = permission.ipv6_ranges().unwrap_or_default();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].cidr_ipv6(), Some("2001:db8::7/128"));
+        assert_eq!(ranges[0].description(), Some(DESCRIPTION));
+    }
+
+    #[test]
+    fn addresses_include_v4_and_every_v6() {
+        let instance = Instance::builder()
+            .public_ip_address("198.51.100.7")
+            .private_ip_address("10.0.0.7")
+            .ipv6_address("2001:db8::7")
+            .network_interfaces(
+                InstanceNetworkInterface::builder()
+                    .ipv6_addresses(
+                        InstanceIpv6Address::builder()
+                            .ipv6_address("2001:db8::7")
+                            .build(),
+                    )
+                    .ipv6_addresses(
+                        InstanceIpv6Address::builder()
+                            .ipv6_address("2001:db8::8")
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+
+        assert_eq!(
+            addresses(&instance),
+            vec![
+                net("10.0.0.7"),
+                net("198.51.100.7"),
+                net("2001:db8::7"),
+                net("2001:db8::8"),
+            ]
+        );
+    }
+
+    #[test]
+    fn addresses_skip_missing_and_bad() {
+        let instance = Instance::builder()
+            .private_ip_address("10.0.0.7")
+            .ipv6_address("not an address")
+            .build();
+
+        assert_eq!(addresses(&instance), vec![net("10.0.0.7")]);
+        assert!(addresses(&Instance::builder().build()).is_empty());
+    }
+}
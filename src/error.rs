@@ -0,0 +1,132 @@
+use arrow::error::ArrowError;
+use parquet::errors::ParquetError;
+
+use crate::parq::SchemaError;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("couldn't fetch from s3: {0}")]
+    S3(#[source] BoxError),
+
+    #[error(transparent)]
+    Schema(#[from] SchemaError),
+
+    /// The object as a whole couldn't be decoded (corrupt parquet, bad gzip, etc)
+    #[error("couldn't decode flow log: {0}")]
+    Decode(#[source] BoxError),
+
+    #[error(transparent)]
+    Row(#[from] RowError),
+
+    #[error("db error: {0}")]
+    Db(#[source] BoxError),
+
+    #[error("ec2 error: {0}")]
+    Ec2(#[source] BoxError),
+
+    #[error("secrets error: {0}")]
+    Secrets(#[source] BoxError),
+
+    /// A grant backend couldn't do what was asked (missing config, unwritable outbox, etc)
+    #[error("grant error: {0}")]
+    Grant(#[source] BoxError),
+}
+
+/// Why a single flow log record wasn't turned into a block
+#[derive(Debug, thiserror::Error)]
+pub enum RowError {
+    #[error("non-block entry ({0:?})")]
+    NotRejected(Option<String>),
+
+    #[error("unknown proto number {0}")]
+    UnknownProto(i32),
+
+    #[error("bad ip {0:?}")]
+    BadIp(String),
+
+    #[error("bad number {0:?}")]
+    BadNumber(String),
+
+    #[error("missing column {0}")]
+    Missing(usize),
+}
+
+impl Error {
+    pub fn s3(err: impl Into<BoxError>) -> Error {
+        Error::S3(err.into())
+    }
+
+    pub fn decode(err: impl Into<BoxError>) -> Error {
+        Error::Decode(err.into())
+    }
+
+    pub fn db(err: impl Into<BoxError>) -> Error {
+        Error::Db(err.into())
+    }
+
+    pub fn ec2(err: impl Into<BoxError>) -> Error {
+        Error::Ec2(err.into())
+    }
+
+    pub fn secrets(err: impl Into<BoxError>) -> Error {
+        Error::Secrets(err.into())
+    }
+
+    pub fn grant(err: impl Into<BoxError>) -> Error {
+        Error::Grant(err.into())
+    }
+
+    /// Short name of the kind of failure, for logs and the failed_objects table
+    pub fn class(&self) -> &'static str {
+        match self {
+            Error::S3(_) => "s3",
+            Error::Schema(_) => "schema",
+            Error::Decode(_) => "decode",
+            Error::Row(_) => "row",
+            Error::Db(_) => "db",
+            Error::Ec2(_) => "ec2",
+            Error::Secrets(_) => "secrets",
+            Error::Grant(_) => "grant",
+        }
+    }
+
+    /// Whether trying again later could succeed; bad data will stay bad
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::S3(_) | Error::Db(_) | Error::Ec2(_) | Error::Secrets(_) | Error::Grant(_) => {
+                true
+            }
+            Error::Schema(_) | Error::Decode(_) | Error::Row(_) => false,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(err: diesel::result::Error) -> Self {
+        Error::db(err)
+    }
+}
+
+impl From<diesel_async::pooled_connection::deadpool::PoolError> for Error {
+    fn from(err: diesel_async::pooled_connection::deadpool::PoolError) -> Self {
+        Error::db(err)
+    }
+}
+
+/// The only external errors parquet sees come from the ranged s3 reads
+impl From<ParquetError> for Error {
+    fn from(err: ParquetError) -> Self {
+        match err {
+            ParquetError::External(err) => Error::S3(err),
+            err => Error::decode(err),
+        }
+    }
+}
+
+impl From<ArrowError> for Error {
+    fn from(err: ArrowError) -> Self {
+        Error::decode(err)
+    }
+}
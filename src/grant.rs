@@ -0,0 +1,223 @@
+use std::fmt;
+use std::str::FromStr;
+
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::AsyncPgConnection;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use ipnetwork::IpNetwork;
+
+use crate::ec2::{self, InstanceInfo};
+use crate::error::Error;
+use crate::models::InetProto;
+use crate::nft;
+
+/// Something a grant was made in: a security group rule, a prefix list entry, a NACL entry or an
+/// nftables set element.
+///
+/// `group_id` is what holds it (group, list, acl, host) and `rule_id` identifies it within that.
+#[derive(Debug)]
+pub struct AddedRule {
+    pub group_id: String,
+    pub rule_id: String,
+}
+
+/// A grant found in the backend itself, whether or not it's in the db
+pub struct Listed {
+    pub rule: AddedRule,
+    pub src: IpNetwork,
+}
+
+/// A way of letting a knocker in.
+///
+/// What a backend creates is recorded in `grants` under its [`Backend::kind`], which is how the
+/// grant finds its way back to the backend to be revoked.
+pub trait GrantBackend: Send + Sync {
+    /// Opens `services` on `info` to `src`, returning everything that was created
+    fn grant<'a>(
+        &'a self,
+        src: IpNetwork,
+        info: &'a InstanceInfo,
+        services: &'a [(InetProto, u16)],
+    ) -> BoxFuture<'a, Result<Vec<AddedRule>, Error>>;
+
+    /// Removes something [`GrantBackend::grant`] created; it already being gone isn't an error
+    fn revoke<'a>(&'a self, rule: &'a AddedRule) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Grants currently in the group, list, acl or host `group_id`
+    fn list<'a>(
+        &'a self,
+        group_id: &'a str,
+        pool: &'a Pool<AsyncPgConnection>,
+    ) -> BoxFuture<'a, Result<Vec<Listed>, Error>>;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Backend {
+    /// A rule per service on the instance's grant groups (the original behaviour)
+    SgRules,
+    /// A host entry in a managed prefix list per address family.
+    ///
+    /// The services opened are whatever the groups referencing the lists allow, and a knocker is
+    /// let in everywhere the list is referenced. Instances tagged `pknocker:prefix-list` (and
+    /// `pknocker:prefix-list-v6`) get their own lists; the configured ones are shared by every
+    /// untagged instance. Saves a group rule per knocker.
+    PrefixList,
+    /// An allow entry per service in the network ACL of the instance's subnet, numbered from the
+    /// configured range. NACLs are stateless, so return traffic has to be allowed already.
+    Nacl,
+    /// Elements added to an nftables set on the host, sent through the outbox (s3 or a directory
+    /// rather than a queue; see [`crate::nft`])
+    Nft,
+}
+
+impl Backend {
+    /// `grants.kind` of what the backend creates
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Backend::SgRules => "sg-rule",
+            Backend::PrefixList => "prefix-list",
+            Backend::Nacl => "nacl-entry",
+            Backend::Nft => "nft",
+        }
+    }
+
+    pub fn get(&self) -> &'static dyn GrantBackend {
+        match self {
+            Backend::SgRules => &SgRules,
+            Backend::PrefixList => &PrefixList,
+            Backend::Nacl => &Nacl,
+            Backend::Nft => &Nft,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.kind())
+    }
+}
+
+/// Takes the `grants.kind` names, plus `rules` for the security group rules
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rules" | "sg-rule" => Ok(Backend::SgRules),
+            "prefix-list" => Ok(Backend::PrefixList),
+            "nacl" | "nacl-entry" => Ok(Backend::Nacl),
+            "nft" => Ok(Backend::Nft),
+            _ => Err(format!("unknown grant backend {s:?}")),
+        }
+    }
+}
+
+struct SgRules;
+
+impl GrantBackend for SgRules {
+    fn grant<'a>(
+        &'a self,
+        src: IpNetwork,
+        info: &'a InstanceInfo,
+        services: &'a [(InetProto, u16)],
+    ) -> BoxFuture<'a, Result<Vec<AddedRule>, Error>> {
+        ec2::add_rules(src, info, services).boxed()
+    }
+
+    fn revoke<'a>(&'a self, rule: &'a AddedRule) -> BoxFuture<'a, Result<(), Error>> {
+        ec2::revoke_rule(&rule.group_id, &rule.rule_id).boxed()
+    }
+
+    fn list<'a>(
+        &'a self,
+        group_id: &'a str,
+        _pool: &'a Pool<AsyncPgConnection>,
+    ) -> BoxFuture<'a, Result<Vec<Listed>, Error>> {
+        ec2::list_rules(group_id).boxed()
+    }
+}
+
+struct PrefixList;
+
+impl GrantBackend for PrefixList {
+    fn grant<'a>(
+        &'a self,
+        src: IpNetwork,
+        info: &'a InstanceInfo,
+        _services: &'a [(InetProto, u16)],
+    ) -> BoxFuture<'a, Result<Vec<AddedRule>, Error>> {
+        async move {
+            let list_id = info.prefix_list(src).ok_or_else(|| {
+                Error::grant(format!("no prefix list for {src} on {}", info.name()))
+            })?;
+
+            Ok(vec![ec2::add_to_prefix_list(list_id, src, info).await?])
+        }
+        .boxed()
+    }
+
+    fn revoke<'a>(&'a self, rule: &'a AddedRule) -> BoxFuture<'a, Result<(), Error>> {
+        ec2::remove_from_prefix_list(&rule.group_id, &rule.rule_id).boxed()
+    }
+
+    fn list<'a>(
+        &'a self,
+        group_id: &'a str,
+        _pool: &'a Pool<AsyncPgConnection>,
+    ) -> BoxFuture<'a, Result<Vec<Listed>, Error>> {
+        ec2::list_prefix_list(group_id).boxed()
+    }
+}
+
+struct Nacl;
+
+impl GrantBackend for Nacl {
+    fn grant<'a>(
+        &'a self,
+        src: IpNetwork,
+        info: &'a InstanceInfo,
+        services: &'a [(InetProto, u16)],
+    ) -> BoxFuture<'a, Result<Vec<AddedRule>, Error>> {
+        ec2::add_nacl_entries(src, info, services).boxed()
+    }
+
+    fn revoke<'a>(&'a self, rule: &'a AddedRule) -> BoxFuture<'a, Result<(), Error>> {
+        ec2::delete_nacl_entry(&rule.group_id, &rule.rule_id).boxed()
+    }
+
+    fn list<'a>(
+        &'a self,
+        group_id: &'a str,
+        _pool: &'a Pool<AsyncPgConnection>,
+    ) -> BoxFuture<'a, Result<Vec<Listed>, Error>> {
+        ec2::list_nacl_entries(group_id).boxed()
+    }
+}
+
+struct Nft;
+
+impl GrantBackend for Nft {
+    fn grant<'a>(
+        &'a self,
+        src: IpNetwork,
+        info: &'a InstanceInfo,
+        services: &'a [(InetProto, u16)],
+    ) -> BoxFuture<'a, Result<Vec<AddedRule>, Error>> {
+        nft::add_elements(src, info, services).boxed()
+    }
+
+    fn revoke<'a>(&'a self, rule: &'a AddedRule) -> BoxFuture<'a, Result<(), Error>> {
+        nft::delete_element(&rule.group_id, &rule.rule_id).boxed()
+    }
+
+    /// The sets live on the hosts, which only ever hear from us, so this is what the db says
+    /// they were sent
+    fn list<'a>(
+        &'a self,
+        group_id: &'a str,
+        pool: &'a Pool<AsyncPgConnection>,
+    ) -> BoxFuture<'a, Result<Vec<Listed>, Error>> {
+        nft::list_elements(group_id, pool).boxed()
+    }
+}
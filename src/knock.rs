@@ -0,0 +1,760 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use hmac::{Hmac, Mac};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::CONFIG;
+use crate::ec2::DEFAULT_SERVICES;
+use crate::models::{Conns, InetProto, Knock};
+
+/// A named knock sequence, the destinations it's valid for and what it opens
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(flatten)]
+    pub sequence: Sequence,
+    /// Networks the profile applies to; every destination when empty
+    #[serde(default)]
+    pub dsts: Vec<IpNetwork>,
+    /// Services to open on a match instead of the instance's own
+    #[serde(default)]
+    pub open: Option<Vec<(InetProto, u16)>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Sequence {
+    /// The same ports every time
+    Static { conns: Conns },
+    /// Ports derived from a shared secret and the current time window
+    Totp { totp: Totp },
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Totp {
+    secret: String,
+    #[serde(default = "Totp::default_length")]
+    length: usize,
+    #[serde(default = "Totp::default_step_secs")]
+    step_secs: i64,
+    /// How far before its window a knock may be stamped; flow logs use the start of their
+    /// aggregation interval
+    #[serde(default = "Totp::default_skew_secs")]
+    skew_secs: i64,
+}
+
+impl fmt::Debug for Totp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Totp")
+            .field("secret", &"<redacted>")
+            .field("length", &self.length)
+            .field("step_secs", &self.step_secs)
+            .field("skew_secs", &self.skew_secs)
+            .finish()
+    }
+}
+
+impl Totp {
+    /// Each knock uses 3 bytes of the sha256 output
+    const MAX_LENGTH: usize = 10;
+    const MIN_PORT: u16 = 1024;
+
+    fn default_length() -> usize {
+        4
+    }
+
+    fn default_step_secs() -> i64 {
+        120
+    }
+
+    fn default_skew_secs() -> i64 {
+        60
+    }
+
+    fn length(&self) -> usize {
+        self.length.clamp(1, Totp::MAX_LENGTH)
+    }
+
+    /// The sequence for the `counter`th time step.
+    ///
+    /// Ports are never below 1024, but nothing keeps one from being a service the dst opens; its
+    /// traffic isn't counted as knocks, so that window's sequence can't be matched and the knocker
+    /// has to wait for the next one.
+    pub fn conns(&self, counter: i64) -> Conns {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("hmac takes keys of any size");
+        mac.update(&counter.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        Conns(
+            hash.chunks_exact(3)
+                .take(self.length())
+                .map(|b| {
+                    let proto = if b[0] & 1 == 0 {
+                        InetProto::Tcp
+                    } else {
+                        InetProto::Udp
+                    };
+                    let range = u16::MAX - Totp::MIN_PORT;
+                    (
+                        proto,
+                        Totp::MIN_PORT + u16::from_be_bytes([b[1], b[2]]) % range,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// The sequences valid at `now` (the current and previous windows) along with the earliest
+    /// knock timestamp that can count towards each
+    pub fn current(&self, now: i64) -> [(Conns, i64); 2] {
+        let step = self.step_secs.max(1);
+        let counter = now.div_euclid(step);
+        [counter, counter - 1].map(|c| (self.conns(c), c * step - self.skew_secs))
+    }
+}
+
+impl Profile {
+    pub const DEFAULT_NAME: &'static str = "default";
+
+    /// A profile for a bare sequence that applies everywhere
+    pub fn from_conns(conns: Conns) -> Profile {
+        Profile {
+            name: Profile::DEFAULT_NAME.to_string(),
+            sequence: Sequence::Static { conns },
+            dsts: Vec::new(),
+            open: None,
+        }
+    }
+
+    pub fn applies_to(&self, dst: IpNetwork) -> bool {
+        self.dsts.is_empty() || self.dsts.iter().any(|net| net.contains(dst.ip()))
+    }
+
+    /// Number of knocks in the sequence
+    pub fn len(&self) -> usize {
+        match &self.sequence {
+            Sequence::Static { conns } => conns.0.len(),
+            Sequence::Totp { totp } => totp.length(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `now` is event time (the latest knock), so a late flow log is judged as of when its
+    /// knocks happened
+    pub fn matches(&self, knocks: &[Knock], now: i64, order: KnockOrder) -> bool {
+        let span = CONFIG.knock_span_secs;
+        match &self.sequence {
+            Sequence::Static { conns } => {
+                matches(conns, knocks, order) && within_span(knocks, span)
+            }
+            Sequence::Totp { totp } => totp.current(now).iter().any(|(conns, not_before)| {
+                let in_window: Vec<Knock> = knocks
+                    .iter()
+                    .filter(|k| k.ts >= *not_before)
+                    .copied()
+                    .collect();
+                matches(conns, &in_window, order) && within_span(&in_window, span)
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn should_block(&self, knocks: &[Knock]) -> bool {
+        knocks.len() >= self.len()
+    }
+
+    /// How many of the wanted knocks were seen, ignoring order; the best of the valid windows for
+    /// a totp sequence
+    pub fn progress(&self, knocks: &[Knock], now: i64) -> usize {
+        match &self.sequence {
+            Sequence::Static { conns } => overlap(conns, knocks),
+            Sequence::Totp { totp } => totp
+                .current(now)
+                .iter()
+                .map(|(conns, _)| overlap(conns, knocks))
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// What to do with the knocks from a src to a dst
+pub enum Verdict<'a> {
+    /// No profile covers the dst
+    Ignore,
+    Grant(&'a Profile),
+    /// Enough knocks to have matched every applicable profile, but none did
+    Deny,
+    /// Not enough knocks yet
+    Wait,
+}
+
+/// The decision [`crate::db::run_checks`] acts on
+pub fn judge<'a>(
+    profiles: &'a [Profile],
+    dst: IpNetwork,
+    knocks: &[Knock],
+    now: i64,
+    order: KnockOrder,
+) -> Verdict<'a> {
+    let applicable: Vec<&Profile> = profiles.iter().filter(|p| p.applies_to(dst)).collect();
+
+    if applicable.is_empty() {
+        Verdict::Ignore
+    } else if let Some(profile) = applicable.iter().find(|p| p.matches(knocks, now, order)) {
+        Verdict::Grant(profile)
+    } else if applicable.iter().all(|p| p.should_block(knocks)) {
+        Verdict::Deny
+    } else {
+        Verdict::Wait
+    }
+}
+
+/// Which of a pair's knocks are looked at and how many make it worth checking; the db query and
+/// the replay both go by this
+#[derive(Debug, Clone)]
+pub struct CheckWindow {
+    /// Knocks more than this before the pair's latest are left out
+    pub secs: i64,
+    pub min_knocks: usize,
+    /// More than this is a scan rather than a knock; twice the longest sequence leaves room for
+    /// stray traffic mixed in
+    pub max_knocks: usize,
+    /// What the profiles open; traffic to those is the service being used, not knocking
+    pub excluded: Vec<(InetProto, u16)>,
+    /// The same for what each instance's `pknocker:open` tag opens, by its address
+    pub excluded_on: Vec<(IpAddr, (InetProto, u16))>,
+}
+
+impl CheckWindow {
+    pub fn new(profiles: &[Profile]) -> CheckWindow {
+        let lengths = profiles.iter().map(Profile::len).filter(|len| *len > 0);
+
+        let mut excluded: Vec<(InetProto, u16)> = profiles
+            .iter()
+            .flat_map(|p| p.open.as_deref().unwrap_or(&DEFAULT_SERVICES))
+            .copied()
+            .collect();
+        excluded.sort();
+        excluded.dedup();
+
+        CheckWindow {
+            secs: CONFIG.knock_window_secs,
+            min_knocks: lengths.clone().min().unwrap_or(1),
+            max_knocks: lengths.max().unwrap_or_default() * 2,
+            excluded,
+            excluded_on: Vec::new(),
+        }
+    }
+
+    /// Also leaves out the services instances open, from [`crate::ec2::open_services`]
+    pub fn with_instances(
+        mut self,
+        services: impl IntoIterator<Item = (IpNetwork, (InetProto, u16))>,
+    ) -> CheckWindow {
+        self.excluded_on
+            .extend(services.into_iter().map(|(ip, service)| (ip.ip(), service)));
+        self.excluded_on.sort();
+        self.excluded_on.dedup();
+        self
+    }
+
+    pub fn excludes(&self, dst: IpNetwork, conn: (InetProto, u16)) -> bool {
+        self.excluded.contains(&conn) || self.excluded_on.contains(&(dst.ip(), conn))
+    }
+
+    /// The (time sorted) knocks that count: not excluded, and close enough to the latest
+    pub fn recent(&self, dst: IpNetwork, knocks: &[Knock]) -> Vec<Knock> {
+        let knocks = knocks
+            .iter()
+            .filter(|k| !self.excludes(dst, k.conn()))
+            .copied();
+        let latest = knocks.clone().map(|k| k.ts).max().unwrap_or_default();
+        knocks.filter(|k| k.ts >= latest - self.secs).collect()
+    }
+
+    /// The knocks to judge, if there's a count worth checking
+    pub fn candidate(&self, dst: IpNetwork, knocks: &[Knock]) -> Option<Vec<Knock>> {
+        let recent = self.recent(dst, knocks);
+        (self.min_knocks..=self.max_knocks)
+            .contains(&recent.len())
+            .then_some(recent)
+    }
+}
+
+/// Size of the multiset intersection of the wanted and seen conns
+fn overlap(wanted: &Conns, knocks: &[Knock]) -> usize {
+    let mut got: Vec<(InetProto, u16)> = knocks.iter().map(Knock::conn).collect();
+    wanted
+        .0
+        .iter()
+        .filter(|conn| match got.iter().position(|g| g == *conn) {
+            Some(idx) => {
+                got.swap_remove(idx);
+                true
+            }
+            None => false,
+        })
+        .count()
+}
+
+/// The knock secret is either a single bare sequence or a list of profiles
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum KnockSecret {
+    Conns(Conns),
+    Profiles(Vec<Profile>),
+}
+
+impl KnockSecret {
+    pub fn into_profiles(self) -> Vec<Profile> {
+        match self {
+            KnockSecret::Conns(conns) => vec![Profile::from_conns(conns)],
+            KnockSecret::Profiles(profiles) => profiles,
+        }
+    }
+}
+
+/// How the knocks seen for a src/dst pair are compared to the wanted sequence
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KnockOrder {
+    /// Any permutation of the wanted ports matches (the original behaviour)
+    Unordered,
+    /// The knocks must arrive in the wanted order.
+    ///
+    /// Flow logs only give us the start of the aggregation window each packet fell in, so knocks
+    /// whose timestamps are within `tie_secs` of each other can't be ordered; `ties` decides
+    /// what to do with them.
+    Ordered { tie_secs: i64, ties: Ties },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Ties {
+    /// Tied knocks may match the wanted sequence in any order
+    AnyOrder,
+    /// Tied knocks never match unless they're all the same port
+    Reject,
+}
+
+pub fn matches(wanted: &Conns, knocks: &[Knock], order: KnockOrder) -> bool {
+    if knocks.len() != wanted.0.len() {
+        return false;
+    }
+
+    match order {
+        KnockOrder::Unordered => {
+            let mut want = wanted.0.clone();
+            want.sort();
+            let mut got: Vec<(InetProto, u16)> = knocks.iter().map(Knock::conn).collect();
+            got.sort();
+            want == got
+        }
+
+        KnockOrder::Ordered { tie_secs, ties } => {
+            let mut want = wanted.0.as_slice();
+            for group in tied_groups(knocks, tie_secs) {
+                let (head, rest) = want.split_at(group.len());
+                want = rest;
+
+                let mut head = head.to_vec();
+                let mut got: Vec<(InetProto, u16)> = group.iter().map(Knock::conn).collect();
+
+                if ties == Ties::Reject && got.windows(2).any(|w| w[0] != w[1]) {
+                    return false;
+                }
+
+                head.sort();
+                got.sort();
+                if head != got {
+                    return false;
+                }
+            }
+            true
+        }
+    }
+}
+
+/// The clock a pair's knocks are judged by: when its latest knock was seen. Flow logs arrive
+/// minutes after the fact, so the wall clock would put a totp sequence in the wrong window.
+pub fn event_time(knocks: &[Knock]) -> i64 {
+    knocks.iter().map(|k| k.ts).max().unwrap_or_default()
+}
+
+/// Whether the knocks could all have happened within `span_secs` of each other.
+///
+/// A flow log only says a packet was seen somewhere between its record's `start` and `end`, so
+/// the knocks fit when the latest start is no more than the span after the earliest end.
+fn within_span(knocks: &[Knock], span_secs: i64) -> bool {
+    let latest_start = knocks.iter().map(|k| k.ts).max();
+    let earliest_end = knocks.iter().map(|k| k.end).min();
+
+    match (latest_start, earliest_end) {
+        (Some(start), Some(end)) => start - end <= span_secs,
+        _ => true,
+    }
+}
+
+/// Splits the (time sorted) knocks into runs whose timestamps are within `tie_secs` of the first
+/// knock of the run
+fn tied_groups(knocks: &[Knock], tie_secs: i64) -> Vec<&[Knock]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+
+    for idx in 1..=knocks.len() {
+        if idx == knocks.len() || knocks[idx].ts - knocks[start].ts > tie_secs {
+            groups.push(&knocks[start..idx]);
+            start = idx;
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::InetProto::{Tcp, Udp};
+
+    const V4_DST: &str = "10.0.0.7";
+    const V6_DST: &str = "2001:db8::7";
+    const ORDER: KnockOrder = KnockOrder::Ordered {
+        tie_secs: 0,
+        ties: Ties::AnyOrder,
+    };
+
+    fn net(ip: &str) -> IpNetwork {
+        ip.parse().unwrap()
+    }
+
+    fn profile() -> Profile {
+        Profile::from_conns(Conns(vec![(Tcp, 7614), (Udp, 1234), (Tcp, 9971)]))
+    }
+
+    fn knocked(conns: &[(InetProto, u16)], start: i64, step: i64) -> Vec<Knock> {
+        conns
+            .iter()
+            .zip(0..)
+            .map(|(&(proto, port), idx)| Knock {
+                proto,
+                port,
+                ts: start + idx * step,
+                end: start + idx * step + 10,
+            })
+            .collect()
+    }
+
+    /// What `run_checks` does with a pair's knocks, less the db and grant backend
+    fn check<'a>(profiles: &'a [Profile], dst: &str, knocks: &[Knock]) -> Option<Verdict<'a>> {
+        let window = CheckWindow::new(profiles);
+        let knocks = window.candidate(net(dst), knocks)?;
+        Some(judge(
+            profiles,
+            net(dst),
+            &knocks,
+            event_time(&knocks),
+            ORDER,
+        ))
+    }
+
+    #[test]
+    fn v4_and_v6_are_granted_alike() {
+        let profiles = [profile()];
+        let knocks = knocked(&[(Tcp, 7614), (Udp, 1234), (Tcp, 9971)], 1_000, 5);
+
+        for dst in [V4_DST, V6_DST] {
+            assert!(
+                matches!(check(&profiles, dst, &knocks), Some(Verdict::Grant(p)) if p.name == Profile::DEFAULT_NAME),
+                "{dst}"
+            );
+        }
+    }
+
+    #[test]
+    fn v4_and_v6_are_denied_alike() {
+        let profiles = [profile()];
+        let knocks = knocked(&[(Tcp, 7614), (Tcp, 9971), (Udp, 1234)], 1_000, 5);
+
+        for dst in [V4_DST, V6_DST] {
+            assert!(
+                matches!(check(&profiles, dst, &knocks), Some(Verdict::Deny)),
+                "{dst}"
+            );
+        }
+    }
+
+    #[test]
+    fn v4_and_v6_wait_alike() {
+        let profiles = [profile()];
+        let two = knocked(&[(Tcp, 7614), (Udp, 1234)], 1_000, 5);
+
+        for dst in [V4_DST, V6_DST] {
+            // Under the shortest sequence isn't a candidate at all
+            assert!(check(&profiles, dst, &two).is_none(), "{dst}");
+        }
+    }
+
+    #[test]
+    fn profile_dsts_scope_v4_and_v6() {
+        let mut v6_only = profile();
+        v6_only.dsts = vec![net("2001:db8::/64")];
+        let profiles = [v6_only];
+        let knocks = knocked(&[(Tcp, 7614), (Udp, 1234), (Tcp, 9971)], 1_000, 5);
+
+        assert!(matches!(
+            check(&profiles, V4_DST, &knocks),
+            Some(Verdict::Ignore)
+        ));
+        assert!(matches!(
+            check(&profiles, V6_DST, &knocks),
+            Some(Verdict::Grant(_))
+        ));
+    }
+
+    #[test]
+    fn opened_services_are_not_knocks() {
+        let profiles = [profile()];
+        let knocks = knocked(
+            &[
+                (Tcp, 7614),
+                (Tcp, 22),
+                (Udp, 1234),
+                (Tcp, 2222),
+                (Tcp, 9971),
+            ],
+            1_000,
+            5,
+        );
+
+        for dst in [V4_DST, V6_DST] {
+            let window = CheckWindow::new(&profiles).with_instances([(net(dst), (Tcp, 2222))]);
+            let candidate = window.candidate(net(dst), &knocks).unwrap();
+
+            assert_eq!(
+                candidate.iter().map(Knock::conn).collect::<Vec<_>>(),
+                vec![(Tcp, 7614), (Udp, 1234), (Tcp, 9971)],
+                "{dst}"
+            );
+            assert!(matches!(
+                judge(
+                    &profiles,
+                    net(dst),
+                    &candidate,
+                    event_time(&candidate),
+                    ORDER
+                ),
+                Verdict::Grant(_)
+            ));
+
+            // Another instance's service is still a knock here
+            let window =
+                CheckWindow::new(&profiles).with_instances([(net("10.9.9.9"), (Tcp, 2222))]);
+            assert_eq!(window.candidate(net(dst), &knocks).unwrap().len(), 4);
+        }
+    }
+
+    #[test]
+    fn knocks_outside_the_window_are_dropped() {
+        let profiles = [profile()];
+        let window = CheckWindow::new(&profiles);
+        let mut knocks = knocked(&[(Tcp, 7614), (Udp, 1234), (Tcp, 9971)], 1_000, 5);
+        knocks.insert(0, knocked(&[(Udp, 4444)], 1_000 - window.secs - 1, 0)[0]);
+
+        for dst in [V4_DST, V6_DST] {
+            assert_eq!(window.recent(net(dst), &knocks).len(), 3, "{dst}");
+        }
+    }
+
+    #[test]
+    fn slow_knocks_are_denied() {
+        let profiles = [profile()];
+        let span = CONFIG.knock_span_secs;
+        let knocks = knocked(&[(Tcp, 7614), (Udp, 1234), (Tcp, 9971)], 1_000, span);
+
+        for dst in [V4_DST, V6_DST] {
+            assert!(
+                matches!(check(&profiles, dst, &knocks), Some(Verdict::Deny)),
+                "{dst}"
+            );
+        }
+    }
+
+    #[test]
+    fn span_allows_for_the_flow_log_interval() {
+        let late_start = Knock {
+            proto: Tcp,
+            port: 1,
+            ts: 200,
+            end: 260,
+        };
+        let early_end = Knock {
+            proto: Tcp,
+            port: 2,
+            ts: 100,
+            end: 150,
+        };
+
+        assert!(within_span(&[late_start, early_end], 50));
+        assert!(!within_span(&[late_start, early_end], 49));
+        assert!(within_span(&[], 0));
+    }
+
+    fn wanted() -> Conns {
+        Conns(vec![(Tcp, 7614), (Udp, 1234), (Tcp, 9971)])
+    }
+
+    /// Knocks on `conns` at the given times
+    fn knocked_at(conns: &[(InetProto, u16)], times: &[i64]) -> Vec<Knock> {
+        conns
+            .iter()
+            .zip(times)
+            .map(|(&(proto, port), &ts)| Knock {
+                proto,
+                port,
+                ts,
+                end: ts + 10,
+            })
+            .collect()
+    }
+
+    fn ordered(tie_secs: i64, ties: Ties) -> KnockOrder {
+        KnockOrder::Ordered { tie_secs, ties }
+    }
+
+    #[test]
+    fn unordered_matches_any_permutation() {
+        let shuffled = [(Udp, 1234), (Tcp, 9971), (Tcp, 7614)];
+        assert!(matches(
+            &wanted(),
+            &knocked(&shuffled, 0, 10),
+            KnockOrder::Unordered
+        ));
+
+        let wrong = [(Udp, 1234), (Tcp, 9971), (Udp, 7614)];
+        assert!(!matches(
+            &wanted(),
+            &knocked(&wrong, 0, 10),
+            KnockOrder::Unordered
+        ));
+
+        let extra = [(Tcp, 7614), (Udp, 1234), (Tcp, 9971), (Tcp, 7614)];
+        assert!(!matches(
+            &wanted(),
+            &knocked(&extra, 0, 10),
+            KnockOrder::Unordered
+        ));
+    }
+
+    #[test]
+    fn ordered_needs_the_wanted_order() {
+        let order = ordered(0, Ties::AnyOrder);
+        assert!(matches(&wanted(), &knocked(&wanted().0, 0, 10), order));
+
+        let swapped = [(Udp, 1234), (Tcp, 7614), (Tcp, 9971)];
+        assert!(!matches(&wanted(), &knocked(&swapped, 0, 10), order));
+    }
+
+    #[test]
+    fn knocks_within_tie_secs_may_swap() {
+        let swapped = knocked_at(&[(Udp, 1234), (Tcp, 7614), (Tcp, 9971)], &[0, 3, 20]);
+
+        assert!(matches(&wanted(), &swapped, ordered(5, Ties::AnyOrder)));
+        assert!(!matches(&wanted(), &swapped, ordered(2, Ties::AnyOrder)));
+    }
+
+    #[test]
+    fn rejected_ties_must_be_one_port() {
+        let tied = knocked_at(&wanted().0, &[0, 0, 20]);
+        assert!(matches(&wanted(), &tied, ordered(0, Ties::AnyOrder)));
+        assert!(!matches(&wanted(), &tied, ordered(0, Ties::Reject)));
+
+        let repeated = Conns(vec![(Tcp, 7614), (Tcp, 7614), (Udp, 1234)]);
+        let tied = knocked_at(&repeated.0, &[0, 0, 20]);
+        assert!(matches(&repeated, &tied, ordered(0, Ties::Reject)));
+    }
+
+    #[test]
+    fn tied_groups_run_from_their_first_knock() {
+        let lens = |times: &[i64], tie_secs| -> Vec<usize> {
+            let conns = vec![(Tcp, 7614); times.len()];
+            tied_groups(&knocked_at(&conns, times), tie_secs)
+                .iter()
+                .map(|group| group.len())
+                .collect()
+        };
+
+        assert_eq!(lens(&[0, 3, 6, 20], 5), [2, 1, 1]);
+        assert_eq!(lens(&[0, 0, 1], 0), [2, 1]);
+        assert_eq!(lens(&[0, 10, 20], 10), [2, 1]);
+        assert!(lens(&[], 5).is_empty());
+    }
+
+    fn totp() -> Totp {
+        Totp {
+            secret: "correct horse battery staple".to_string(),
+            length: 4,
+            step_secs: 120,
+            skew_secs: 60,
+        }
+    }
+
+    fn totp_profile() -> Profile {
+        Profile {
+            name: "totp".to_string(),
+            sequence: Sequence::Totp { totp: totp() },
+            dsts: Vec::new(),
+            open: None,
+        }
+    }
+
+    #[test]
+    fn totp_ports_are_the_hmac_of_the_counter() {
+        assert_eq!(
+            totp().conns(0).0,
+            [(Tcp, 20996), (Tcp, 30012), (Udp, 63102), (Tcp, 48492)]
+        );
+        assert_eq!(
+            totp().conns(1).0,
+            [(Tcp, 21737), (Tcp, 12700), (Tcp, 43866), (Udp, 43264)]
+        );
+    }
+
+    #[test]
+    fn totp_ports_are_never_well_known() {
+        for counter in 0..1000 {
+            let conns = totp().conns(counter);
+            assert_eq!(conns.0.len(), 4);
+            assert!(conns.0.iter().all(|&(_, port)| port >= Totp::MIN_PORT));
+        }
+    }
+
+    #[test]
+    fn previous_totp_window_is_accepted() {
+        // Knocked late in window 9, judged early in window 10
+        let knocks = knocked(&totp().conns(9).0, 9 * 120 + 100, 5);
+        assert!(totp_profile().matches(&knocks, 10 * 120 + 5, ORDER));
+        assert!(totp_profile().matches(&knocks, 9 * 120 + 115, ORDER));
+    }
+
+    #[test]
+    fn totp_windows_two_back_are_rejected() {
+        let knocks = knocked(&totp().conns(9).0, 9 * 120 + 100, 5);
+        assert!(!totp_profile().matches(&knocks, 11 * 120 + 5, ORDER));
+    }
+
+    #[test]
+    fn totp_knocks_before_the_skew_are_dropped() {
+        // Window 10's sequence, with its first knock stamped more than the skew before it
+        let knocks = knocked_at(&totp().conns(10).0, &[10 * 120 - 61, 1150, 1160, 1170]);
+        assert!(!totp_profile().matches(&knocks, 1205, ORDER));
+
+        let knocks = knocked_at(&totp().conns(10).0, &[10 * 120 - 60, 1150, 1160, 1170]);
+        assert!(totp_profile().matches(&knocks, 1205, ORDER));
+    }
+}
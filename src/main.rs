@@ -1,64 +1,139 @@
 use std::ops::Deref;
 
 use aws_lambda_events::event::s3::S3Event;
-use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use lambda_runtime::{Error, LambdaEvent};
 use once_cell::sync::Lazy;
-use tracing::log::error;
+use serde_json::Value;
+use tracing::log::{error, info, warn};
 
+use crate::cli::{Command, USAGE};
+use crate::config::CONFIG;
+use crate::dry_run::DryRun;
+use crate::knock::Profile;
 use crate::models::Conns;
 use crate::models::InetProto::{Tcp, Udp};
 
 mod aws;
+mod cli;
+mod config;
 mod db;
+mod dry_run;
 mod ec2;
+mod error;
+mod grant;
+mod knock;
+mod migrate;
 mod models;
+mod nft;
 mod parq;
+mod replay;
 mod s3;
 mod schema;
 mod secrets;
+mod text;
 
-async fn function_handler(event: LambdaEvent<S3Event>) -> Result<(), Error> {
-    let (db_conn_info, _) = secrets::get_conn_info().await?;
+/// Handles both s3 notifications and scheduled (EventBridge) invocations; anything that isn't an s3
+/// event just expires old grants and cleans up.
+///
+/// A dry run returns (and logs) the report of what would have been done.
+async fn function_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let dry_run = (CONFIG.dry_run
+        || event.payload.get("dry_run").and_then(Value::as_bool) == Some(true))
+    .then(DryRun::default);
+
+    handle(event.payload, dry_run.as_ref()).await?;
+
+    match dry_run {
+        Some(dry_run) => {
+            let report = serde_json::to_value(dry_run.into_report())?;
+            info!("Dry run report :: {report}");
+            Ok(report)
+        }
+        None => Ok(Value::Null),
+    }
+}
+
+async fn handle(payload: Value, dry_run: Option<&DryRun>) -> Result<(), Error> {
+    let (db_conn_info, profiles) = secrets::get_conn_info().await?;
+    let profiles = wanted_profiles(profiles)?;
     let pool = db::get_pool(db_conn_info).await?;
+    migrate::on_cold_start(&pool, dry_run.is_some()).await?;
+
+    if dry_run.is_some() {
+        info!("Dry run; not expiring or cleaning");
+    } else {
+        if let Err(err) = db::expire_grants(&pool).await {
+            error!("Error expiring grants: {err:?}")
+        };
+        if let Err(err) = db::clean(&pool).await {
+            error!("Error cleaning: {err:?}")
+        };
+    }
 
-    if let Err(err) = db::clean(&pool).await {
-        error!("Error cleaning: {err:?}")
-    };
-    s3::get_and_parse(event.payload, &pool).await;
-    db::run_checks(&pool).await
+    match serde_json::from_value::<S3Event>(payload) {
+        Ok(s3_event) if !s3_event.records.is_empty() => {
+            let outcomes = s3::get_and_parse(s3_event, &pool, dry_run).await;
+            let checks = db::run_checks(&pool, &profiles, dry_run).await;
+
+            // Failing the invocation has lambda retry (and eventually dead letter) the event
+            if !outcomes.retry.is_empty() {
+                return Err(format!("Couldn't ingest {:?}", outcomes.retry).into());
+            }
+
+            match checks {
+                // Only have lambda retry the event when doing so could help
+                Err(err) if err.is_retryable() => Err(err.into()),
+                Err(err) => {
+                    error!("Error running checks: {err}");
+                    Ok(())
+                }
+                Ok(_) => Ok(()),
+            }
+        }
+        _ => {
+            info!("Not an s3 event; only expired and cleaned");
+            Ok(())
+        }
+    }
 }
 
-const PRINT_WANTED: bool = false;
+/// The knock profiles from secrets. Falling back to the compiled in sequence when they couldn't
+/// be loaded has to be opted into; a bad edit during a rotation would otherwise quietly bring
+/// back an old (possibly leaked) sequence.
+fn wanted_profiles(profiles: Option<Vec<Profile>>) -> Result<Vec<Profile>, error::Error> {
+    match profiles {
+        Some(profiles) => Ok(profiles),
+        None if CONFIG.allow_builtin_conns => {
+            warn!("Falling back to the compiled in conn list");
+            Ok(vec![Profile::from_conns(WANTED_CONNS.clone())])
+        }
+        None => Err(error::Error::secrets(
+            "no usable knock profiles and PKNOCKER_ALLOW_BUILTIN_CONNS isn't set",
+        )),
+    }
+}
 
 static WANTED_CONNS: Lazy<Conns> = Lazy::new(|| {
-    let mut v = vec![
+    Conns(vec![
         (Tcp, 7614),
         (Udp, 1234),
         (Tcp, 9971),
         (Udp, 1234),
         (Udp, 23657),
         (Tcp, 9911),
-    ];
-    v.sort();
-    Conns(v)
+    ])
 });
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     init().await;
 
-    if PRINT_WANTED {
-        print_wanted().await
-    } else if false {
-        let (db_conn_info, _) = secrets::get_conn_info().await?;
-        let pool = db::get_pool(db_conn_info).await?;
-
-        db::insert_test_data(&pool).await?;
-        db::run_checks(&pool).await?;
-
-        Ok(())
-    } else {
-        run(service_fn(function_handler)).await
+    match Command::from_env() {
+        Ok(command) => command.run().await,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            std::process::exit(2);
+        }
     }
 }
 
@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, HashSet};
+
+use diesel::sql_types::{BigInt, Bool, Text};
+use diesel::QueryableByName;
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::{AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection};
+use tokio::sync::OnceCell;
+use tracing::log::{error, info, warn};
+
+use crate::config::CONFIG;
+use crate::error::Error;
+
+/// The `migrations/` directory, oldest first; a new migration has to be added here as well
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "00000000000000_diesel_initial_setup",
+        include_str!("../migrations/00000000000000_diesel_initial_setup/up.sql"),
+    ),
+    (
+        "2023-04-30-201135_create_blocks",
+        include_str!("../migrations/2023-04-30-201135_create_blocks/up.sql"),
+    ),
+    (
+        "2023-05-01-065946_create_denies",
+        include_str!("../migrations/2023-05-01-065946_create_denies/up.sql"),
+    ),
+    (
+        "2023-05-03-045615_create_added",
+        include_str!("../migrations/2023-05-03-045615_create_added/up.sql"),
+    ),
+    (
+        "2023-05-03-050019_create_cleaner",
+        include_str!("../migrations/2023-05-03-050019_create_cleaner/up.sql"),
+    ),
+    (
+        "2023-05-03-051252_view_to_check",
+        include_str!("../migrations/2023-05-03-051252_view_to_check/up.sql"),
+    ),
+    (
+        "2023-05-14-031502_ordered_view_to_check",
+        include_str!("../migrations/2023-05-14-031502_ordered_view_to_check/up.sql"),
+    ),
+    (
+        "2023-05-16-042233_create_grants",
+        include_str!("../migrations/2023-05-16-042233_create_grants/up.sql"),
+    ),
+    (
+        "2023-05-18-220417_added_profile",
+        include_str!("../migrations/2023-05-18-220417_added_profile/up.sql"),
+    ),
+    (
+        "2023-05-21-174920_create_failed_objects",
+        include_str!("../migrations/2023-05-21-174920_create_failed_objects/up.sql"),
+    ),
+    (
+        "2023-05-22-013318_idempotent_ingest",
+        include_str!("../migrations/2023-05-22-013318_idempotent_ingest/up.sql"),
+    ),
+    (
+        "2023-05-24-061207_grant_kind",
+        include_str!("../migrations/2023-05-24-061207_grant_kind/up.sql"),
+    ),
+    (
+        "2023-05-26-042711_drop_view_to_check",
+        include_str!("../migrations/2023-05-26-042711_drop_view_to_check/up.sql"),
+    ),
+    (
+        "2023-05-28-051930_block_event_end",
+        include_str!("../migrations/2023-05-28-051930_block_event_end/up.sql"),
+    ),
+];
+
+/// What the tables are expected to look like
+const SCHEMA: &str = include_str!("schema.rs");
+
+/// Held while migrating so concurrent cold starts take turns
+const LOCK_KEY: i64 = 0x706b_6e6f_636b;
+
+static MIGRATED: OnceCell<()> = OnceCell::const_new();
+
+#[derive(QueryableByName)]
+struct Applied {
+    #[diesel(sql_type = Text)]
+    version: String,
+}
+
+#[derive(QueryableByName)]
+struct DbColumn {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+    #[diesel(sql_type = Text)]
+    udt_name: String,
+    #[diesel(sql_type = Bool)]
+    nullable: bool,
+}
+
+/// Migrates (unless turned off) and checks the schema once per container.
+///
+/// A dry run never migrates; it only checks the schema, and leaves migrating to the next real run.
+pub async fn on_cold_start(pool: &Pool<AsyncPgConnection>, dry_run: bool) -> Result<(), Error> {
+    if dry_run {
+        if MIGRATED.initialized() {
+            return Ok(());
+        }
+        return check_schema(&mut *pool.get().await?).await;
+    }
+
+    MIGRATED
+        .get_or_try_init(|| async {
+            if CONFIG.migrate {
+                run(pool).await?;
+            } else {
+                check_schema(&mut *pool.get().await?).await?;
+            }
+            Ok::<_, Error>(())
+        })
+        .await?;
+    Ok(())
+}
+
+/// Applies the pending migrations, recording them where the diesel cli does so either can be
+/// used, then checks the result against `schema.rs`. Returns the migrations that were applied.
+pub async fn run(pool: &Pool<AsyncPgConnection>) -> Result<Vec<&'static str>, Error> {
+    let mut conn = pool.get().await?;
+
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(LOCK_KEY)
+        .execute(&mut conn)
+        .await?;
+
+    let applied = apply(&mut conn).await;
+
+    diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(LOCK_KEY)
+        .execute(&mut conn)
+        .await?;
+
+    let applied = applied?;
+    check_schema(&mut conn).await?;
+    Ok(applied)
+}
+
+async fn apply(conn: &mut AsyncPgConnection) -> Result<Vec<&'static str>, Error> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+            version VARCHAR(50) PRIMARY KEY NOT NULL,
+            run_on  TIMESTAMP             NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .await?;
+
+    let done: HashSet<String> = diesel::sql_query("SELECT version FROM __diesel_schema_migrations")
+        .load::<Applied>(conn)
+        .await?
+        .into_iter()
+        .map(|a| a.version)
+        .collect();
+
+    let mut applied = Vec::new();
+    for &(name, sql) in MIGRATIONS {
+        let version = version(name);
+        if done.contains(&version) {
+            continue;
+        }
+
+        info!("Applying migration {name}");
+        // A multi statement batch runs as one transaction, so a failed migration leaves nothing
+        // half done
+        conn.batch_execute(&format!(
+            "{sql}\n;\nINSERT INTO __diesel_schema_migrations (version) VALUES ('{version}');"
+        ))
+        .await
+        .map_err(|e| {
+            error!("Migration {name} failed: {e}");
+            Error::db(e)
+        })?;
+        applied.push(name);
+    }
+
+    Ok(applied)
+}
+
+/// Diesel's version is the directory's timestamp without the dashes
+fn version(name: &str) -> String {
+    name.split('_').next().unwrap_or(name).replace('-', "")
+}
+
+/// Fails if the db and `schema.rs` diverge; anything that gets here would otherwise surface as
+/// confusing query errors later
+async fn check_schema(conn: &mut AsyncPgConnection) -> Result<(), Error> {
+    let actual: BTreeMap<(String, String), (String, bool)> = diesel::sql_query(
+        "SELECT table_name::TEXT, column_name::TEXT, udt_name::TEXT, is_nullable = 'YES' AS nullable
+         FROM information_schema.columns
+         WHERE table_schema = current_schema()
+           AND table_name <> '__diesel_schema_migrations'",
+    )
+    .load::<DbColumn>(conn)
+    .await?
+    .into_iter()
+    .map(|c| ((c.table_name, c.column_name), (c.udt_name, c.nullable)))
+    .collect();
+
+    let diverged = diverged(&expected_columns(SCHEMA), &actual);
+    if diverged.is_empty() {
+        Ok(())
+    } else {
+        error!("THE DATABASE DOESN'T MATCH schema.rs :: {diverged:?}");
+        Err(Error::db(format!(
+            "database schema diverges from schema.rs: {diverged:?}"
+        )))
+    }
+}
+
+/// A column of `schema.rs` that's missing from the db or has a different type breaks queries, and
+/// a non-null db column that `schema.rs` doesn't know (say from a newer migration) breaks inserts
+fn diverged(
+    expected: &[(&str, &str, &str)],
+    actual: &BTreeMap<(String, String), (String, bool)>,
+) -> Vec<String> {
+    let mut diverged = Vec::new();
+    for (table, column, sql_type) in expected {
+        match actual.get(&(table.to_string(), column.to_string())) {
+            None => diverged.push(format!("{table}.{column} is missing")),
+            Some((udt, _)) if *udt != udt_name(sql_type) => {
+                diverged.push(format!("{table}.{column} is {udt}, not {sql_type}"))
+            }
+            Some(_) => (),
+        }
+    }
+
+    for ((table, column), (udt, nullable)) in actual {
+        let known = expected.iter().any(|(t, c, _)| t == table && c == column);
+        if !known && !nullable {
+            diverged.push(format!("{table}.{column} ({udt}) isn't in schema.rs"));
+        }
+    }
+
+    diverged
+}
+
+/// `(table, column, type)` for every column of every `table!` in the generated schema
+fn expected_columns(schema: &str) -> Vec<(&str, &str, &str)> {
+    let mut columns = Vec::new();
+    let mut table = None;
+
+    for line in schema.lines().map(str::trim) {
+        if line.ends_with('{') && line.contains('(') {
+            table = line.split_whitespace().next();
+        } else if line == "}" {
+            table = None;
+        } else if let (Some(table), Some((column, sql_type))) = (table, line.split_once("->")) {
+            let sql_type = sql_type.trim().trim_end_matches(',');
+            columns.push((table, column.trim(), sql_type));
+        }
+    }
+
+    columns
+}
+
+fn udt_name(sql_type: &str) -> String {
+    let sql_type = sql_type
+        .strip_prefix("Nullable<")
+        .and_then(|t| t.strip_suffix('>'))
+        .unwrap_or(sql_type);
+
+    match sql_type {
+        "Int2" => "int2".to_string(),
+        "Int4" => "int4".to_string(),
+        "Int8" => "int8".to_string(),
+        "Text" => "text".to_string(),
+        "Varchar" => "varchar".to_string(),
+        "Bool" => "bool".to_string(),
+        "Inet" => "inet".to_string(),
+        "Timestamp" => "timestamp".to_string(),
+        "Timestamptz" => "timestamptz".to_string(),
+        "Jsonb" => "jsonb".to_string(),
+        // Custom types are named in snake case
+        other => {
+            let mut name = String::new();
+            for (idx, c) in other.chars().enumerate() {
+                if c.is_ascii_uppercase() && idx > 0 {
+                    name.push('_');
+                }
+                name.push(c.to_ascii_lowercase());
+            }
+            if name.is_empty() {
+                warn!("Empty sql type in schema.rs");
+            }
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actual(columns: &[(&str, &str, &str, bool)]) -> BTreeMap<(String, String), (String, bool)> {
+        columns
+            .iter()
+            .map(|&(t, c, udt, nullable)| {
+                ((t.to_string(), c.to_string()), (udt.to_string(), nullable))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn migrations_match_the_directory() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().unwrap().is_dir())
+            .map(|e| e.file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+
+        let listed: Vec<&str> = MIGRATIONS.iter().map(|&(name, _)| name).collect();
+        assert_eq!(names, listed);
+
+        for &(name, sql) in MIGRATIONS {
+            let on_disk = std::fs::read_to_string(dir.join(name).join("up.sql")).unwrap();
+            assert_eq!(on_disk, sql, "{name}");
+        }
+    }
+
+    #[test]
+    fn version_is_the_timestamp() {
+        assert_eq!(
+            version("2023-05-22-013318_idempotent_ingest"),
+            "20230522013318"
+        );
+        assert_eq!(
+            version("00000000000000_diesel_initial_setup"),
+            "00000000000000"
+        );
+    }
+
+    #[test]
+    fn expected_columns_reads_every_table() {
+        let columns = expected_columns(SCHEMA);
+        assert!(columns.contains(&("blocks", "id", "Int8")));
+        assert!(columns.contains(&("blocks", "proto", "InetProto")));
+        assert!(columns.contains(&("blocks", "src_port", "Nullable<Int4>")));
+        assert!(columns.contains(&("added", "profile", "Text")));
+        assert!(columns.iter().all(|(_, column, _)| !column.contains(' ')));
+    }
+
+    #[test]
+    fn udt_names() {
+        assert_eq!(udt_name("Int4"), "int4");
+        assert_eq!(udt_name("Nullable<Int4>"), "int4");
+        assert_eq!(udt_name("Timestamptz"), "timestamptz");
+        assert_eq!(udt_name("InetProto"), "inet_proto");
+        assert_eq!(udt_name("Nullable<InetProto>"), "inet_proto");
+    }
+
+    #[test]
+    fn divergence_is_reported_both_ways() {
+        let expected = [("blocks", "id", "Int8"), ("blocks", "port", "Int4")];
+
+        let same = actual(&[
+            ("blocks", "id", "int8", false),
+            ("blocks", "port", "int4", false),
+        ]);
+        assert!(diverged(&expected, &same).is_empty());
+
+        let missing = actual(&[("blocks", "id", "int8", false)]);
+        assert_eq!(diverged(&expected, &missing), ["blocks.port is missing"]);
+
+        let retyped = actual(&[
+            ("blocks", "id", "int8", false),
+            ("blocks", "port", "int8", false),
+        ]);
+        assert_eq!(
+            diverged(&expected, &retyped),
+            ["blocks.port is int8, not Int4"]
+        );
+
+        let extra = actual(&[
+            ("blocks", "id", "int8", false),
+            ("blocks", "port", "int4", false),
+            ("blocks", "note", "text", true),
+            ("blocks", "flags", "int4", false),
+        ]);
+        assert_eq!(
+            diverged(&expected, &extra),
+            ["blocks.flags (int4) isn't in schema.rs"]
+        );
+    }
+}
@@ -3,13 +3,24 @@ use diesel::prelude::*;
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub struct Conns(pub Vec<(InetProto, u16)>);
 
-impl Conns {
+/// A single rejected connection as aggregated by the check query (`[proto, port, start epoch
+/// secs, end epoch secs]`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct Knock {
+    pub proto: InetProto,
+    pub port: u16,
+    pub ts: i64,
+    /// The end of the flow log interval the knock was seen in; it happened somewhere in `ts..=end`
+    pub end: i64,
+}
+
+impl Knock {
     #[inline]
-    pub fn should_block(&self, that: Conns) -> bool {
-        that.0.len() >= self.0.len()
+    pub fn conn(&self) -> (InetProto, u16) {
+        (self.proto, self.port)
     }
 }
 
@@ -34,6 +45,25 @@ pub enum InetProto {
     Icmp,
 }
 
+impl InetProto {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InetProto::Tcp => "tcp",
+            InetProto::Udp => "udp",
+            InetProto::Icmp => "icmp",
+        }
+    }
+
+    /// The IANA protocol number, as NACL entries want it
+    pub fn number(&self) -> i32 {
+        match self {
+            InetProto::Tcp => 6,
+            InetProto::Udp => 17,
+            InetProto::Icmp => 1,
+        }
+    }
+}
+
 #[derive(Queryable, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[diesel(table_name = crate::schema::blocks)]
 pub struct Block {
@@ -44,9 +74,11 @@ pub struct Block {
     pub port: i32,
     pub event_ts: NaiveDateTime,
     pub insert_ts: NaiveDateTime,
+    pub src_port: Option<i32>,
+    pub event_end: NaiveDateTime,
 }
 
-#[derive(Insertable, Debug)]
+#[derive(Insertable, Queryable, Debug)]
 #[diesel(table_name = crate::schema::blocks)]
 pub struct NewBlock {
     pub src_ip: IpNetwork,
@@ -54,6 +86,9 @@ pub struct NewBlock {
     pub proto: InetProto,
     pub port: i32,
     pub event_ts: DateTime<Utc>,
+    pub event_end: DateTime<Utc>,
+    /// Tells apart knocks on the same port in the same second; `None` when the log doesn't have it
+    pub src_port: Option<i32>,
 }
 
 #[derive(Insertable, Debug)]
@@ -61,8 +96,51 @@ pub struct NewBlock {
 pub struct ToAdd {
     pub src_ip: IpNetwork,
     pub dst_ip: IpNetwork,
+    pub profile: String,
 }
 
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::failed_objects)]
+pub struct NewFailedObject {
+    pub bucket: String,
+    pub key: String,
+    pub error_class: String,
+    pub error: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::ingested_objects)]
+pub struct NewIngestedObject {
+    pub bucket: String,
+    pub key: String,
+    pub etag: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::grants)]
+pub struct NewGrant {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub group_id: String,
+    pub rule_id: String,
+    pub expires_at: DateTime<Utc>,
+    pub kind: String,
+}
+
+#[derive(Queryable, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[diesel(table_name = crate::schema::grants)]
+pub struct Grant {
+    pub id: i64,
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub group_id: String,
+    pub rule_id: String,
+    pub expires_at: DateTime<Utc>,
+    pub added_on: DateTime<Utc>,
+    pub kind: String,
+}
+
+#[allow(dead_code)]
 #[derive(Queryable, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[diesel(table_name = crate::schema::denies)]
 pub struct Denies {
@@ -70,21 +148,13 @@ pub struct Denies {
     pub added_on: DateTime<Utc>,
 }
 
-#[derive(Queryable, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
-#[diesel(table_name = crate::schema::denies)]
-pub struct ViewToCheck {
+/// A src/dst pair worth checking and its knocks, as loaded by [`crate::db::run_checks`]
+#[derive(QueryableByName, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct ToCheck {
+    #[diesel(sql_type = diesel::sql_types::Inet)]
     pub src_ip: IpNetwork,
+    #[diesel(sql_type = diesel::sql_types::Inet)]
     pub dst_ip: IpNetwork,
+    #[diesel(sql_type = diesel::sql_types::Text)]
     pub conns: String,
 }
-
-table! {
-    use diesel::sql_types::*;
-    use crate::schema::sql_types::InetProto;
-
-    view_to_check (src_ip) {
-        src_ip -> Inet,
-        dst_ip -> Inet,
-        conns -> Text,
-    }
-}
@@ -0,0 +1,154 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use chrono::Utc;
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::AsyncPgConnection;
+use ipnetwork::IpNetwork;
+use tracing::log::info;
+
+use crate::config::CONFIG;
+use crate::ec2::InstanceInfo;
+use crate::error::Error;
+use crate::grant::{AddedRule, Backend, Listed};
+use crate::models::InetProto;
+
+/// Hosts are expected to have
+///
+/// ```text
+/// table inet pknocker {
+///     set allowed_v4 { type ipv4_addr . inet_proto . inet_service; flags timeout; }
+///     set allowed_v6 { type ipv6_addr . inet_proto . inet_service; flags timeout; }
+/// }
+/// ```
+///
+/// and to apply the scripts sent to them with `nft -f`, in name order.
+const TABLE: &str = "inet pknocker";
+
+/// Keeps the names of scripts written in the same microsecond in order
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Sends the host an element per service; they time out with the grant so a lost delete can't
+/// leave the host open
+pub async fn add_elements(
+    src: IpNetwork,
+    info: &InstanceInfo,
+    services: &[(InetProto, u16)],
+) -> Result<Vec<AddedRule>, Error> {
+    let set = set_for(src);
+    let timeout = info.ttl().num_seconds();
+    let elements: Vec<String> = services
+        .iter()
+        .map(|&(proto, port)| format!("{} . {} . {port}", src.ip(), proto.name()))
+        .collect();
+
+    let script: String = elements
+        .iter()
+        .map(|e| format!("add element {TABLE} {set} {{ {e} timeout {timeout}s }}\n"))
+        .collect();
+    send(info.instance_id(), &script).await?;
+    info!("Sent {src} to {set} on {}", info.name());
+
+    Ok(elements
+        .into_iter()
+        .map(|rule_id| AddedRule {
+            group_id: info.instance_id().to_string(),
+            rule_id,
+        })
+        .collect())
+}
+
+/// The element may have timed out already, in which case the host's `nft -f` fails and there's
+/// nothing left to do
+pub async fn delete_element(host: &str, element: &str) -> Result<(), Error> {
+    let src = element
+        .split_whitespace()
+        .next()
+        .and_then(|ip| ip.parse::<IpNetwork>().ok())
+        .ok_or_else(|| Error::grant(format!("bad nft element {element:?}")))?;
+
+    let set = set_for(src);
+    send(
+        host,
+        &format!("delete element {TABLE} {set} {{ {element} }}\n"),
+    )
+    .await?;
+    info!("Sent delete of {element} from {set} to {host}");
+    Ok(())
+}
+
+/// The elements recorded in `grants` as sent to the host and not yet revoked; what the host
+/// actually has can't be read back
+pub async fn list_elements(
+    host: &str,
+    pool: &Pool<AsyncPgConnection>,
+) -> Result<Vec<Listed>, Error> {
+    Ok(crate::db::grants_in(Backend::Nft, host, pool)
+        .await?
+        .into_iter()
+        .map(|grant| Listed {
+            src: grant.src_ip,
+            rule: AddedRule {
+                group_id: grant.group_id,
+                rule_id: grant.rule_id,
+            },
+        })
+        .collect())
+}
+
+fn set_for(ip: IpNetwork) -> &'static str {
+    match ip {
+        IpNetwork::V4(_) => "allowed_v4",
+        IpNetwork::V6(_) => "allowed_v6",
+    }
+}
+
+/// Writes the script to the host's outbox: objects under `s3://bucket/prefix/<host>/` or files in
+/// `<dir>/<host>/`.
+///
+/// This stands in for sending to an SQS queue, which would need another sdk client; hosts that
+/// want a queue can have the bucket's event notifications for their prefix deliver to one.
+async fn send(host: &str, script: &str) -> Result<(), Error> {
+    let outbox = CONFIG
+        .nft_outbox
+        .as_deref()
+        .ok_or_else(|| Error::grant("PKNOCKER_NFT_OUTBOX isn't set"))?;
+    let name = format!(
+        "{}-{:06}.nft",
+        Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+        SEQ.fetch_add(1, Ordering::Relaxed) % 1_000_000
+    );
+
+    match outbox.strip_prefix("s3://") {
+        Some(path) => {
+            let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+            let key = [prefix.trim_matches('/'), host, &name]
+                .into_iter()
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            Client::new(crate::aws::get_conf().await)
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(ByteStream::from(script.as_bytes().to_vec()))
+                .send()
+                .await
+                .map_err(Error::s3)?;
+        }
+        None => {
+            let dir = Path::new(outbox).join(host);
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .map_err(Error::grant)?;
+            tokio::fs::write(dir.join(name), script)
+                .await
+                .map_err(Error::grant)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,164 +1,417 @@
+use std::fmt;
 use std::str::FromStr;
 
+use arrow::array::{Array, AsArray, Int32Array, Int64Array, StringArray};
+use arrow::compute::{and, eq_scalar, eq_utf8_scalar, filter, filter_record_batch, or};
+use arrow::datatypes::{Int32Type, Int64Type};
+use arrow::record_batch::RecordBatch;
 use bytes::Bytes;
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use diesel_async::pooled_connection::deadpool::Pool;
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_async::AsyncPgConnection;
+use futures_util::TryStreamExt;
 use ipnetwork::IpNetwork;
-use lambda_runtime::Error;
-use parquet::arrow::ProjectionMask;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::async_reader::AsyncFileReader;
+use parquet::arrow::{ParquetRecordBatchStreamBuilder, ProjectionMask};
 use parquet::file::metadata::ParquetMetaData;
-use parquet::file::reader::FileReader;
-use parquet::file::reader::SerializedFileReader;
-use parquet::record::{Row, RowAccessor};
 use tracing::log::{debug, error, info};
 
+use crate::dry_run::DryRun;
+use crate::error::{Error, RowError};
 use crate::models::{InetProto, NewBlock};
-use crate::schema::blocks;
 
-pub async fn add_records(
-    data: Vec<u8>,
+/// Rows decoded (and so at most inserted) at a time; 7 binds per row keeps each insert under
+/// postgres' 65535 bind limit
+pub const BATCH_SIZE: usize = 8192;
+
+const PROTO_TCP: i32 = 6;
+const PROTO_UDP: i32 = 17;
+
+/// Streams the REJECT records out of a parquet file, inserting them a batch at a time so the whole
+/// file never has to be in memory.
+///
+/// Only the columns we need are decoded.
+pub async fn add_records<T: AsyncFileReader + Unpin + Send + 'static>(
+    input: T,
     pool: &Pool<AsyncPgConnection>,
-    add: bool,
+    dry_run: Option<&DryRun>,
 ) -> Result<(), Error> {
-    let reader = SerializedFileReader::new(Bytes::from(data))?;
-    let (fields, _) = Fields::from_metadata(reader.metadata());
+    let builder = ParquetRecordBatchStreamBuilder::new(input).await?;
+    let (fields, mask) = Fields::from_metadata(builder.metadata())?;
+    let fields = fields.projected();
 
-    let mut to_add = Vec::with_capacity(reader.num_row_groups());
-    let rows = reader.get_row_iter(None)?;
+    let mut stream = builder
+        .with_projection(mask)
+        .with_batch_size(BATCH_SIZE)
+        .build()?;
 
-    for row in rows {
-        match fields.to_block(row) {
-            Err(e) => debug!("Error adding row - {e}"),
+    while let Some(batch) = stream.try_next().await? {
+        add_batch(&fields, &batch, pool, dry_run).await?;
+    }
 
-            Ok(block) => {
-                if !add {
-                    info!("Would add {to_add:?}");
-                } else {
-                    to_add.push(block);
-                }
-            }
-        };
+    Ok(())
+}
+
+/// Same as [`add_records`] for a file that's already in memory
+pub async fn add_records_bytes(
+    data: Bytes,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    let (fields, reader) = bytes_reader(data)?;
+
+    for batch in reader {
+        add_batch(&fields, &batch?, pool, dry_run).await?;
     }
 
-    if !to_add.is_empty() {
-        let mut conn = pool.get().await?;
+    Ok(())
+}
+
+/// Every REJECT record of a file that's already in memory, without touching the db
+pub fn blocks_from_bytes(data: Bytes) -> Result<Vec<NewBlock>, Error> {
+    let (fields, reader) = bytes_reader(data)?;
 
-        let res = diesel::insert_into(blocks::table)
-            .values(&to_add)
-            .execute(&mut conn)
-            .await;
+    let mut blocks = Vec::new();
+    for batch in reader {
+        blocks.extend(fields.batch_to_blocks(&batch?)?);
+    }
 
-        if res.is_ok() {
-            info!("Added {to_add:?}")
-        } else {
-            error!("Couldn't add {to_add:?}: {res:?}")
+    Ok(blocks)
+}
+
+fn bytes_reader(data: Bytes) -> Result<(Fields, ParquetRecordBatchReader), Error> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(data)?;
+    let (fields, mask) = Fields::from_metadata(builder.metadata())?;
+
+    let reader = builder
+        .with_projection(mask)
+        .with_batch_size(BATCH_SIZE)
+        .build()?;
+
+    Ok((fields.projected(), reader))
+}
+
+async fn add_batch(
+    fields: &Fields,
+    batch: &RecordBatch,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    let to_add = fields.batch_to_blocks(batch)?;
+
+    match dry_run {
+        Some(dry_run) => {
+            info!("Would add {} blocks", to_add.len());
+            dry_run.blocks(&to_add);
+            Ok(())
         }
+        None => crate::db::add_blocks(to_add, pool).await,
     }
+}
 
-    Ok(())
+/// Why a flow log's columns couldn't be mapped to the fields we need
+#[derive(Debug, Default)]
+pub struct SchemaError {
+    pub missing: Vec<&'static str>,
+    pub duplicated: Vec<String>,
 }
 
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unusable flow log schema (missing: {:?}, duplicated: {:?})",
+            self.missing, self.duplicated
+        )
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Column indexes of the fields we care about in a flow log; shared by the parquet and text
+/// formats
 #[derive(Default)]
-struct WantFields {
+pub struct WantFields {
     src: Option<usize>,
     dst: Option<usize>,
+    pkt_src: Option<usize>,
+    pkt_dst: Option<usize>,
+    src_port: Option<usize>,
     port: Option<usize>,
     proto: Option<usize>,
     start: Option<usize>,
+    end: Option<usize>,
     action: Option<usize>,
+    duplicated: Vec<String>,
 }
 
 impl WantFields {
-    fn field(&mut self, field_name: &str, idx: usize) {
-        if let Some(field) = match field_name {
+    /// Records the index of a field; text headers use `-` where parquet uses `_` so either works
+    pub fn field(&mut self, field_name: &str, idx: usize) {
+        let field_name = field_name.replace('-', "_");
+        if let Some(field) = match field_name.as_str() {
             "srcaddr" => Some(&mut self.src),
             "dstaddr" => Some(&mut self.dst),
+            "pkt_srcaddr" => Some(&mut self.pkt_src),
+            "pkt_dstaddr" => Some(&mut self.pkt_dst),
+            "srcport" => Some(&mut self.src_port),
             "dstport" => Some(&mut self.port),
             "protocol" => Some(&mut self.proto),
             "start" => Some(&mut self.start),
+            "end" => Some(&mut self.end),
             "action" => Some(&mut self.action),
             _ => None,
         } {
-            if let Some(old) = field.replace(idx) {
-                panic!("Dupe idx for {field_name} ({idx}/{old}");
+            if field.replace(idx).is_some() && !self.duplicated.contains(&field_name) {
+                self.duplicated.push(field_name);
             }
         }
     }
 
-    fn build(self) -> Fields {
-        Fields {
-            src: self.src.unwrap(),
-            dst: self.dst.unwrap(),
-            port: self.port.unwrap(),
-            proto: self.proto.unwrap(),
-            start: self.start.unwrap(),
-            action: self.action.unwrap(),
+    /// The packet level addresses are used over the interface ones when the log has them; without
+    /// an `end` each knock is taken to have happened at its `start`, and without a `srcport`
+    /// re-delivered rows are only caught by the object being marked ingested
+    pub fn build(self) -> Result<Fields, SchemaError> {
+        let mut err = SchemaError {
+            duplicated: self.duplicated,
+            ..SchemaError::default()
+        };
+        let mut need = |idx: Option<usize>, name: &'static str| {
+            if idx.is_none() {
+                err.missing.push(name);
+            }
+            idx.unwrap_or_default()
+        };
+
+        let fields = Fields {
+            src: need(self.pkt_src.or(self.src), "srcaddr"),
+            dst: need(self.pkt_dst.or(self.dst), "dstaddr"),
+            src_port: self.src_port,
+            port: need(self.port, "dstport"),
+            proto: need(self.proto, "protocol"),
+            start: need(self.start, "start"),
+            end: self.end,
+            action: need(self.action, "action"),
+        };
+
+        if err.missing.is_empty() && err.duplicated.is_empty() {
+            Ok(fields)
+        } else {
+            Err(err)
         }
     }
 }
 
-struct Fields {
+pub struct Fields {
     src: usize,
     dst: usize,
+    src_port: Option<usize>,
     port: usize,
     proto: usize,
     start: usize,
+    end: Option<usize>,
     action: usize,
 }
 
 impl Fields {
-    fn from_metadata(metadata: &ParquetMetaData) -> (Fields, ProjectionMask) {
+    fn from_metadata(metadata: &ParquetMetaData) -> Result<(Fields, ProjectionMask), SchemaError> {
         let file_metadata = metadata.file_metadata();
         let mut want_fields = WantFields::default();
         for (idx, field) in file_metadata.schema_descr().columns().iter().enumerate() {
             want_fields.field(field.name(), idx);
         }
 
-        let fields = want_fields.build();
+        let fields = want_fields.build()?;
         let mask = ProjectionMask::roots(file_metadata.schema_descr(), fields.all());
 
-        (fields, mask)
+        Ok((fields, mask))
+    }
+
+    /// The same fields indexed into the projected columns, which keep the file's order
+    fn projected(&self) -> Fields {
+        let mut all = self.all();
+        all.sort_unstable();
+        let pos = |idx: usize| all.binary_search(&idx).expect("field is in all");
+
+        Fields {
+            src: pos(self.src),
+            dst: pos(self.dst),
+            src_port: self.src_port.map(pos),
+            port: pos(self.port),
+            proto: pos(self.proto),
+            start: pos(self.start),
+            end: self.end.map(pos),
+            action: pos(self.action),
+        }
     }
 
-    fn all(&self) -> [usize; 6] {
-        [
+    fn all(&self) -> Vec<usize> {
+        let mut all = vec![
             self.src,
             self.dst,
             self.port,
             self.proto,
             self.start,
             self.action,
-        ]
+        ];
+        all.extend(self.src_port);
+        all.extend(self.end);
+        all
+    }
+
+    /// Converts the REJECTed tcp/udp rows of a decoded batch; the filtering and protocol mapping
+    /// are done on the whole columns before any rows are built
+    fn batch_to_blocks(&self, batch: &RecordBatch) -> Result<Vec<NewBlock>, Error> {
+        let action = string_col(batch, self.action)?;
+        let proto = int_col(batch, self.proto)?;
+
+        let is_tcp = eq_scalar(proto, PROTO_TCP)?;
+        let is_udp = eq_scalar(proto, PROTO_UDP)?;
+        let keep = and(&eq_utf8_scalar(action, "REJECT")?, &or(&is_tcp, &is_udp)?)?;
+        debug!("Keeping {} of {} rows", keep.true_count(), batch.num_rows());
+
+        let batch = filter_record_batch(batch, &keep)?;
+        let is_tcp = filter(&is_tcp, &keep)?;
+        let is_tcp = is_tcp.as_boolean();
+
+        let src = string_col(&batch, self.src)?;
+        let dst = string_col(&batch, self.dst)?;
+        let src_port = self.src_port.map(|idx| int_col(&batch, idx)).transpose()?;
+        let port = int_col(&batch, self.port)?;
+        let start = long_col(&batch, self.start)?;
+        let end = self.end.map(|idx| long_col(&batch, idx)).transpose()?;
+
+        Ok((0..batch.num_rows())
+            .filter_map(|i| {
+                let nulls = [
+                    src.is_null(i),
+                    dst.is_null(i),
+                    port.is_null(i),
+                    start.is_null(i),
+                ];
+                if nulls.contains(&true) {
+                    debug!("Error adding row - null field in row {i}");
+                    return None;
+                }
+
+                let proto = if is_tcp.value(i) {
+                    InetProto::Tcp
+                } else {
+                    InetProto::Udp
+                };
+
+                new_block(
+                    proto,
+                    src.value(i),
+                    dst.value(i),
+                    src_port.filter(|p| !p.is_null(i)).map(|p| p.value(i)),
+                    port.value(i),
+                    start.value(i),
+                    match end {
+                        Some(end) if !end.is_null(i) => end.value(i),
+                        _ => start.value(i),
+                    },
+                )
+                .map_err(|e| debug!("Error adding row - {e}"))
+                .ok()
+            })
+            .collect())
     }
 
-    fn to_block(&self, row: Row) -> Result<NewBlock, Error> {
-        match row.get_string(self.action) {
-            Ok(s) if s == "REJECT" => (),
-            Ok(s) => return Err(Error::from(format!("non-block entry ({s})"))),
-            _ => (),
+    /// Converts a line of a text flow log split on whitespace
+    pub fn text_to_block(&self, cols: &[&str]) -> Result<NewBlock, RowError> {
+        let col = |idx: usize| match cols.get(idx) {
+            None | Some(&"-") => Err(RowError::Missing(idx)),
+            Some(s) => Ok(*s),
         };
 
-        let proto = match row.get_int(self.proto) {
-            Ok(6) => InetProto::Tcp,
-            Ok(17) => InetProto::Udp,
-            Ok(n) => return Err(Error::from(format!("Unknown proto number {n}"))),
-            Err(e) => return Err(Error::from(e)),
+        match col(self.action)? {
+            "REJECT" => (),
+            s => return Err(RowError::NotRejected(Some(s.to_string()))),
         };
 
-        let ts_secs = row.get_long(self.start)?;
-        Ok(NewBlock {
-            src_ip: IpNetwork::from_str(row.get_string(self.src)?)?,
-            dst_ip: IpNetwork::from_str(row.get_string(self.dst)?)?,
-            proto,
-            port: row.get_int(self.port)?,
-            event_ts: match Utc.timestamp_opt(ts_secs, 0).single() {
-                Some(ts) => ts,
-                None => {
-                    error!("Invalid timestamp :: {ts_secs}");
-                    Utc::now()
-                }
-            },
-        })
+        let src_port = match self.src_port.map(col) {
+            Some(Ok(s)) => Some(number(s)?),
+            _ => None,
+        };
+        let start = number(col(self.start)?)?;
+        let end = match self.end {
+            Some(idx) => number(col(idx)?)?,
+            None => start,
+        };
+
+        new_block(
+            proto_from_number(number(col(self.proto)?)?)?,
+            col(self.src)?,
+            col(self.dst)?,
+            src_port,
+            number(col(self.port)?)?,
+            start,
+            end,
+        )
+    }
+}
+
+fn string_col(batch: &RecordBatch, idx: usize) -> Result<&StringArray, Error> {
+    batch
+        .column(idx)
+        .as_string_opt::<i32>()
+        .ok_or_else(|| Error::decode(format!("column {idx} isn't a string")))
+}
+
+fn int_col(batch: &RecordBatch, idx: usize) -> Result<&Int32Array, Error> {
+    batch
+        .column(idx)
+        .as_primitive_opt::<Int32Type>()
+        .ok_or_else(|| Error::decode(format!("column {idx} isn't an int")))
+}
+
+fn long_col(batch: &RecordBatch, idx: usize) -> Result<&Int64Array, Error> {
+    batch
+        .column(idx)
+        .as_primitive_opt::<Int64Type>()
+        .ok_or_else(|| Error::decode(format!("column {idx} isn't a long")))
+}
+
+fn number<T: FromStr>(s: &str) -> Result<T, RowError> {
+    s.parse().map_err(|_| RowError::BadNumber(s.to_string()))
+}
+
+fn proto_from_number(proto: i32) -> Result<InetProto, RowError> {
+    match proto {
+        PROTO_TCP => Ok(InetProto::Tcp),
+        PROTO_UDP => Ok(InetProto::Udp),
+        n => Err(RowError::UnknownProto(n)),
+    }
+}
+
+fn new_block(
+    proto: InetProto,
+    src: &str,
+    dst: &str,
+    src_port: Option<i32>,
+    port: i32,
+    start_secs: i64,
+    end_secs: i64,
+) -> Result<NewBlock, RowError> {
+    Ok(NewBlock {
+        src_ip: IpNetwork::from_str(src).map_err(|_| RowError::BadIp(src.to_string()))?,
+        dst_ip: IpNetwork::from_str(dst).map_err(|_| RowError::BadIp(dst.to_string()))?,
+        proto,
+        port,
+        event_ts: timestamp(start_secs),
+        event_end: timestamp(end_secs),
+        src_port,
+    })
+}
+
+fn timestamp(secs: i64) -> DateTime<Utc> {
+    match Utc.timestamp_opt(secs, 0).single() {
+        Some(ts) => ts,
+        None => {
+            error!("Invalid timestamp :: {secs}");
+            Utc::now()
+        }
     }
 }
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use chrono::{DateTime, TimeZone, Utc};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+use tracing::log::{info, warn};
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::knock::{event_time, judge, CheckWindow, Profile, Verdict};
+use crate::models::{InetProto, Knock, NewBlock};
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayReport {
+    pub files: usize,
+    pub blocks: usize,
+    pub granted: Vec<Granted>,
+    pub denied: Vec<Denied>,
+    /// Pairs that were never granted or denied but got within one knock of a profile
+    pub near_misses: Vec<NearMiss>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Granted {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub profile: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Denied {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub at: DateTime<Utc>,
+    pub knocks: Vec<(InetProto, u16)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NearMiss {
+    pub src_ip: IpNetwork,
+    pub dst_ip: IpNetwork,
+    pub profile: String,
+    pub seen: usize,
+    pub wanted: usize,
+}
+
+/// The blocks, denies and added pairs a replay has built up, standing in for the db tables; a
+/// dry run judges its blocks with one too
+#[derive(Default)]
+pub struct Store {
+    /// Deduped the way `unique_block_idx` does, in the order they were read
+    blocks: BTreeMap<(IpNetwork, IpNetwork), Vec<Knock>>,
+    seen: HashSet<(IpNetwork, IpNetwork, InetProto, Option<i32>, i32, i64)>,
+    /// When each src was denied
+    denies: HashMap<IpNetwork, i64>,
+    /// When each pair's grant expires
+    added: HashMap<(IpNetwork, IpNetwork), i64>,
+    /// The latest knock added, which is as far as the store's clock has got
+    latest: i64,
+}
+
+impl Store {
+    /// How long `clean_db` keeps blocks and denies
+    const KEEP_SECS: i64 = 24 * 60 * 60;
+
+    pub fn add(&mut self, block: NewBlock) {
+        let ts = block.event_ts.timestamp();
+        self.latest = self.latest.max(ts);
+        let key = (
+            block.src_ip,
+            block.dst_ip,
+            block.proto,
+            block.src_port,
+            block.port,
+            ts,
+        );
+        // Rows without a source port can't be told apart, so like the db they're all kept
+        if block.src_port.is_some() && !self.seen.insert(key) {
+            return;
+        }
+        let Ok(port) = u16::try_from(block.port) else {
+            return;
+        };
+
+        self.blocks
+            .entry((block.src_ip, block.dst_ip))
+            .or_default()
+            .push(Knock {
+                proto: block.proto,
+                port,
+                ts,
+                end: block.event_end.timestamp(),
+            });
+    }
+
+    pub fn deny(&mut self, src: IpNetwork, at: i64) {
+        self.denies.insert(src, at);
+    }
+
+    pub fn let_in(&mut self, src: IpNetwork, dst: IpNetwork, until: i64) {
+        self.added.insert((src, dst), until);
+    }
+
+    /// Does what `expire_grants` and `clean_db` would have by the latest knock: a pair whose grant
+    /// is over loses its knocks along with it, and blocks and denies go after a day
+    fn expire(&mut self) {
+        let now = self.latest;
+
+        let expired: Vec<_> = self
+            .added
+            .iter()
+            .filter(|(_, &until)| until <= now)
+            .map(|(&pair, _)| pair)
+            .collect();
+        for (src, dst) in expired {
+            self.added.remove(&(src, dst));
+            self.blocks.remove(&(src, dst));
+            self.seen.retain(|seen| (seen.0, seen.1) != (src, dst));
+        }
+
+        let cutoff = now - Store::KEEP_SECS;
+        self.denies.retain(|_, at| *at >= cutoff);
+        self.seen.retain(|seen| seen.5 >= cutoff);
+        self.blocks.retain(|_, knocks| {
+            knocks.retain(|k| k.ts >= cutoff);
+            !knocks.is_empty()
+        });
+    }
+
+    /// Whether the pair has any blocks, judged or not
+    pub fn has(&self, src: IpNetwork, dst: IpNetwork) -> bool {
+        self.blocks.contains_key(&(src, dst))
+    }
+
+    /// The pairs without a deny or grant, with their knocks oldest first
+    fn pending(&self) -> impl Iterator<Item = (IpNetwork, IpNetwork, Vec<Knock>)> + '_ {
+        self.blocks
+            .iter()
+            .filter(|((src, dst), _)| {
+                !self.denies.contains_key(src) && !self.added.contains_key(&(*src, *dst))
+            })
+            .map(|(&(src, dst), knocks)| {
+                let mut knocks = knocks.clone();
+                // Stable, so ties keep insertion order like the query's `ORDER BY event_ts, id`
+                knocks.sort_by_key(|k| k.ts);
+                (src, dst, knocks)
+            })
+    }
+
+    /// What the db's check query would return
+    pub fn candidates(&self, window: &CheckWindow) -> Vec<(IpNetwork, IpNetwork, Vec<Knock>)> {
+        self.pending()
+            .filter_map(|(src, dst, knocks)| Some((src, dst, window.candidate(dst, &knocks)?)))
+            .collect()
+    }
+}
+
+/// Runs every parquet file in `dir` (in name order) through the checks as if each had just been
+/// ingested, without touching the db or any grant backend.
+///
+/// The instances' `pknocker:open` services are left out of the knocks like the db check does
+/// when they can be described; offline only the profiles' services are.
+pub async fn replay(dir: &Path, profiles: &[Profile]) -> Result<ReplayReport, Error> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(Error::decode)?;
+    while let Some(entry) = entries.next_entry().await.map_err(Error::decode)? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "parquet") {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let (window, described) = match crate::ec2::open_services().await {
+        Ok(services) => (CheckWindow::new(profiles).with_instances(services), true),
+        Err(err) => {
+            warn!("Couldn't describe instances, only excluding the profiles' services: {err}");
+            (CheckWindow::new(profiles), false)
+        }
+    };
+    let mut report = ReplayReport::default();
+    let mut store = Store::default();
+
+    for file in files {
+        info!("Replaying {}", file.display());
+        let data = tokio::fs::read(&file).await.map_err(Error::decode)?;
+        let blocks = crate::parq::blocks_from_bytes(data.into())?;
+
+        report.files += 1;
+        report.blocks += blocks.len();
+
+        for block in blocks {
+            store.add(block);
+        }
+
+        store.expire();
+        check(&mut store, profiles, &window, described, &mut report).await;
+    }
+
+    report.near_misses = near_misses(&store, profiles, &window);
+    Ok(report)
+}
+
+/// One pass of `run_checks` over the store, judging each pair as of its latest knock like it does.
+///
+/// Grants last the instance's ttl when the instances could be described, the configured one when
+/// they couldn't.
+async fn check(
+    store: &mut Store,
+    profiles: &[Profile],
+    window: &CheckWindow,
+    described: bool,
+    report: &mut ReplayReport,
+) {
+    for (src, dst, knocks) in store.candidates(window) {
+        let now = event_time(&knocks);
+        let at = Utc.timestamp_opt(now, 0).single().unwrap_or_default();
+
+        match judge(profiles, dst, &knocks, now, CONFIG.order) {
+            Verdict::Ignore | Verdict::Wait => (),
+            Verdict::Grant(profile) => {
+                let info = if described {
+                    crate::ec2::lookup(dst).await.ok().flatten()
+                } else {
+                    None
+                };
+                let ttl = info.map_or(CONFIG.grant_ttl_secs, |info| info.ttl().num_seconds());
+                store.let_in(src, dst, now + ttl);
+                report.granted.push(Granted {
+                    src_ip: src,
+                    dst_ip: dst,
+                    profile: profile.name.clone(),
+                    at,
+                });
+            }
+            Verdict::Deny => {
+                store.deny(src, now);
+                report.denied.push(Denied {
+                    src_ip: src,
+                    dst_ip: dst,
+                    at,
+                    knocks: knocks.iter().map(Knock::conn).collect(),
+                });
+            }
+        }
+    }
+}
+
+fn near_misses(store: &Store, profiles: &[Profile], window: &CheckWindow) -> Vec<NearMiss> {
+    store
+        .pending()
+        .flat_map(|(src, dst, knocks)| {
+            let knocks = window.recent(dst, &knocks);
+            let now = event_time(&knocks);
+
+            profiles
+                .iter()
+                .filter(|p| p.applies_to(dst) && !p.is_empty())
+                .map(|p| (p, p.progress(&knocks, now)))
+                .filter(|(p, seen)| *seen > 0 && seen + 1 >= p.len())
+                .max_by_key(|(_, seen)| *seen)
+                .map(|(p, seen)| NearMiss {
+                    src_ip: src,
+                    dst_ip: dst,
+                    profile: p.name.clone(),
+                    seen,
+                    wanted: p.len(),
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::InetProto::*;
+
+    const SRC: &str = "73.5.159.5";
+    const DST: &str = "10.0.0.7";
+    const START: i64 = 1_682_484_986;
+    const DAY: i64 = Store::KEEP_SECS;
+
+    fn net(ip: &str) -> IpNetwork {
+        ip.parse().unwrap()
+    }
+
+    fn block(src: &str, port: i32, ts: i64) -> NewBlock {
+        NewBlock {
+            src_ip: net(src),
+            dst_ip: net(DST),
+            proto: Tcp,
+            port,
+            event_ts: Utc.timestamp_opt(ts, 0).unwrap(),
+            event_end: Utc.timestamp_opt(ts + 10, 0).unwrap(),
+            src_port: Some(40_000),
+        }
+    }
+
+    fn ports(store: &Store) -> Vec<u16> {
+        store
+            .pending()
+            .flat_map(|(_, _, knocks)| knocks)
+            .map(|k| k.port)
+            .collect()
+    }
+
+    #[test]
+    fn blocks_go_after_a_day() {
+        let mut store = Store::default();
+        store.add(block(SRC, 7614, START));
+        store.add(block(SRC, 1234, START + DAY));
+
+        store.expire();
+        assert_eq!(ports(&store), [7614, 1234]);
+
+        store.add(block(SRC, 9971, START + DAY + 1));
+        store.expire();
+        assert_eq!(ports(&store), [1234, 9971]);
+
+        // Forgotten, so a re-delivery is taken as new like it would be once the row is cleaned
+        store.add(block(SRC, 7614, START));
+        assert_eq!(ports(&store), [7614, 1234, 9971]);
+    }
+
+    #[test]
+    fn denies_go_after_a_day() {
+        let mut store = Store::default();
+        store.deny(net(SRC), START);
+        store.add(block(SRC, 7614, START + DAY));
+
+        store.expire();
+        assert!(ports(&store).is_empty());
+
+        store.add(block(SRC, 1234, START + DAY + 1));
+        store.expire();
+        assert_eq!(ports(&store), [7614, 1234]);
+    }
+
+    #[test]
+    fn grants_take_their_knocks_with_them() {
+        let mut store = Store::default();
+        store.add(block(SRC, 7614, START));
+        store.let_in(net(SRC), net(DST), START + 3600);
+        store.add(block("73.5.159.6", 7614, START));
+
+        store.add(block(SRC, 1234, START + 3599));
+        store.expire();
+        assert_eq!(ports(&store), [7614]);
+
+        store.add(block(SRC, 9971, START + 3600));
+        store.expire();
+        assert_eq!(ports(&store), [7614]);
+        assert!(store.has(net("73.5.159.6"), net(DST)));
+        assert!(!store.has(net(SRC), net(DST)));
+    }
+}
@@ -1,12 +1,92 @@
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::Arc;
+
 use aws_lambda_events::s3::S3Event;
 use aws_sdk_s3::Client;
+use bytes::Bytes;
 use diesel_async::pooled_connection::deadpool::Pool;
 use diesel_async::AsyncPgConnection;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use parquet::arrow::async_reader::{fetch_parquet_metadata, AsyncFileReader};
+use parquet::errors::ParquetError;
+use parquet::file::metadata::ParquetMetaData;
+use tokio::io::AsyncRead;
 use tracing::log::{error, info};
 use urlencoding::decode;
 
-pub async fn get_and_parse(event: S3Event, pool: &Pool<AsyncPgConnection>) {
+use crate::dry_run::DryRun;
+use crate::error::Error;
+use crate::models::{NewFailedObject, NewIngestedObject};
+
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+
+/// Enough to get the footer and metadata of a flow log in one request
+const FOOTER_SIZE_HINT: usize = 64 * 1024;
+
+/// Parquet files smaller than this are fetched in one request instead of streamed
+const IN_MEMORY_MAX: usize = 8 * 1024 * 1024;
+
+/// The flow log formats we know how to ingest
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Parquet,
+    /// Space delimited text, gzipped or not
+    Text,
+}
+
+impl Format {
+    /// Sniffs the format from the magic bytes, falling back to the key's suffix
+    fn detect(key: Option<&str>, data: &[u8]) -> Format {
+        if data.starts_with(PARQUET_MAGIC) {
+            Format::Parquet
+        } else if crate::text::is_gzip(data) {
+            Format::Text
+        } else {
+            match key {
+                Some(k) if k.ends_with(".log") || k.ends_with(".txt") || k.ends_with(".gz") => {
+                    Format::Text
+                }
+                _ => Format::Parquet,
+            }
+        }
+    }
+}
+
+/// Adds the records of a flow log that's already in memory, eg one read from disk
+pub async fn add_data(
+    name: &str,
+    data: Vec<u8>,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    match Format::detect(Some(name), &data) {
+        Format::Parquet => crate::parq::add_records_bytes(data.into(), pool, dry_run).await,
+        Format::Text => crate::text::add_records(Cursor::new(data), pool, dry_run).await,
+    }
+}
+
+/// What happened to each object of an s3 event
+#[derive(Debug, Default)]
+pub struct Outcomes {
+    pub added: Vec<String>,
+    /// Objects that were re-delivered after already being ingested
+    pub duplicate: Vec<String>,
+    /// Objects that failed in a way that retrying the event could fix
+    pub retry: Vec<String>,
+    /// Objects that will never work; these are in the failed_objects table
+    pub failed: Vec<String>,
+}
+
+/// In a dry run nothing is recorded as ingested or failed, so the same objects can be tried again
+pub async fn get_and_parse(
+    event: S3Event,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Outcomes {
     let client = Client::new(crate::aws::get_conf().await);
+    let mut outcomes = Outcomes::default();
 
     for rec in event.records {
         let bucket = rec.s3.bucket.name;
@@ -18,24 +98,187 @@ pub async fn get_and_parse(event: S3Event, pool: &Pool<AsyncPgConnection>) {
 
         info!("Attempting to connect to {bucket:?}/{key:?}");
 
-        match client
+        let (Some(bucket), Some(key)) = (bucket, key) else {
+            error!("Record is missing the bucket or key");
+            continue;
+        };
+
+        let obj = format!("{bucket}/{key}");
+        match parse(&client, bucket.clone(), key.clone(), pool, dry_run).await {
+            Ok(true) => outcomes.added.push(obj),
+            Ok(false) => outcomes.duplicate.push(obj),
+
+            Err(err) if err.is_retryable() => {
+                error!("Couldn't add block records from {obj}, will retry: {err}");
+                outcomes.retry.push(obj);
+            }
+
+            Err(err) => {
+                error!("Couldn't add block records from {obj}: {err}");
+                let failed = NewFailedObject {
+                    bucket,
+                    key,
+                    error_class: err.class().to_string(),
+                    error: err.to_string(),
+                };
+
+                // If it can't be recorded it'd be lost, so have it retried instead
+                if dry_run.is_some() {
+                    outcomes.failed.push(obj);
+                } else if let Err(err) = crate::db::add_failed_object(failed, pool).await {
+                    error!("Couldn't record {obj} as failed: {err}");
+                    outcomes.retry.push(obj);
+                } else {
+                    outcomes.failed.push(obj);
+                }
+            }
+        }
+    }
+
+    info!("Ingest outcomes :: {outcomes:?}");
+    outcomes
+}
+
+/// Adds the object's records, returning false if this version of it was already added
+async fn parse(
+    client: &Client,
+    bucket: String,
+    key: String,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<bool, Error> {
+    let mut reader = S3Reader::new(client.clone(), bucket, key).await?;
+
+    let ingested = NewIngestedObject {
+        bucket: reader.bucket.clone(),
+        key: reader.key.clone(),
+        etag: reader.etag.clone(),
+    };
+    if crate::db::is_ingested(&ingested, pool).await? {
+        info!("Already ingested {ingested:?}");
+        return Ok(false);
+    }
+
+    let head = reader
+        .get_bytes(0..PARQUET_MAGIC.len().min(reader.size))
+        .await?;
+    let format = Format::detect(Some(&reader.key), &head);
+    info!("Parsing {}/{} as {format:?}", reader.bucket, reader.key);
+
+    match format {
+        Format::Parquet if reader.size <= IN_MEMORY_MAX => {
+            crate::parq::add_records_bytes(reader.get_all().await?.into(), pool, dry_run).await
+        }
+        Format::Parquet => crate::parq::add_records(reader, pool, dry_run).await,
+        Format::Text => crate::text::add_records(reader.body().await?, pool, dry_run).await,
+    }?;
+
+    if dry_run.is_none() {
+        crate::db::add_ingested(ingested, pool).await?;
+    }
+    Ok(true)
+}
+
+/// Reads an s3 object with ranged gets so parquet files can be streamed rather than downloaded
+pub struct S3Reader {
+    client: Client,
+    bucket: String,
+    key: String,
+    etag: String,
+    size: usize,
+}
+
+impl S3Reader {
+    pub async fn new(client: Client, bucket: String, key: String) -> Result<S3Reader, Error> {
+        let head = client
+            .head_object()
+            .bucket(&bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(Error::s3)?;
+
+        Ok(S3Reader {
+            client,
+            bucket,
+            key,
+            etag: head.e_tag().unwrap_or_default().to_string(),
+            size: usize::try_from(head.content_length()).map_err(Error::s3)?,
+        })
+    }
+
+    /// The whole object, for the formats that can't be read in pieces
+    pub async fn get_all(&self) -> Result<Vec<u8>, Error> {
+        let resp = self
+            .client
             .get_object()
-            .set_bucket(bucket)
-            .set_key(key)
+            .bucket(&self.bucket)
+            .key(&self.key)
             .send()
             .await
-        {
-            Err(err) => error!("Couldn't get bucket: {err:?}"),
+            .map_err(Error::s3)?;
 
-            Ok(resp) => match resp.body.collect().await {
-                Err(err) => error!("Couldn't get bucket obj: {err:?}"),
+        Ok(resp.body.collect().await.map_err(Error::s3)?.to_vec())
+    }
 
-                Ok(body) => {
-                    if let Err(err) = crate::parq::add_records(body.to_vec(), pool, true).await {
-                        error!("Couldn't add block records: {err:?}")
-                    }
-                }
-            },
+    /// The whole object as it downloads, for the formats that are read front to back
+    pub async fn body(&self) -> Result<impl AsyncRead + Send + Unpin + 'static, Error> {
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .send()
+            .await
+            .map_err(Error::s3)?;
+
+        Ok(resp.body.into_async_read())
+    }
+}
+
+async fn get_range(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    range: Range<usize>,
+) -> parquet::errors::Result<Bytes> {
+    if range.is_empty() {
+        return Ok(Bytes::new());
+    }
+
+    let resp = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .range(format!("bytes={}-{}", range.start, range.end - 1))
+        .send()
+        .await
+        .map_err(|e| ParquetError::External(Box::new(e)))?;
+
+    Ok(resp
+        .body
+        .collect()
+        .await
+        .map_err(|e| ParquetError::External(Box::new(e)))?
+        .into_bytes())
+}
+
+impl AsyncFileReader for S3Reader {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, parquet::errors::Result<Bytes>> {
+        get_range(&self.client, &self.bucket, &self.key, range).boxed()
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, parquet::errors::Result<Arc<ParquetMetaData>>> {
+        async move {
+            let metadata = fetch_parquet_metadata(
+                |range| get_range(&self.client, &self.bucket, &self.key, range),
+                self.size,
+                Some(FOOTER_SIZE_HINT),
+            )
+            .await?;
+
+            Ok(Arc::new(metadata))
         }
+        .boxed()
     }
 }
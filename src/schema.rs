@@ -11,6 +11,7 @@ diesel::table! {
         src_ip -> Inet,
         dst_ip -> Inet,
         added_on -> Timestamptz,
+        profile -> Text,
     }
 }
 
@@ -26,6 +27,8 @@ diesel::table! {
         port -> Int4,
         event_ts -> Timestamptz,
         insert_ts -> Timestamptz,
+        src_port -> Nullable<Int4>,
+        event_end -> Timestamptz,
     }
 }
 
@@ -36,4 +39,44 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(added, blocks, denies,);
+diesel::table! {
+    failed_objects (id) {
+        id -> Int8,
+        bucket -> Text,
+        key -> Text,
+        error_class -> Text,
+        error -> Text,
+        failed_on -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    grants (id) {
+        id -> Int8,
+        src_ip -> Inet,
+        dst_ip -> Inet,
+        group_id -> Text,
+        rule_id -> Text,
+        expires_at -> Timestamptz,
+        added_on -> Timestamptz,
+        kind -> Text,
+    }
+}
+
+diesel::table! {
+    ingested_objects (bucket, key, etag) {
+        bucket -> Text,
+        key -> Text,
+        etag -> Text,
+        ingested_on -> Timestamptz,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    added,
+    blocks,
+    denies,
+    failed_objects,
+    grants,
+    ingested_objects,
+);
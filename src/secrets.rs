@@ -1,8 +1,8 @@
-use lambda_runtime::Error;
 use serde::Deserialize;
-use tracing::log::info;
+use tracing::log::{error, info};
 
-use crate::models::Conns;
+use crate::error::Error;
+use crate::knock::{KnockSecret, Profile};
 
 #[derive(Deserialize, Debug)]
 pub struct DbConnSecret {
@@ -24,7 +24,11 @@ impl DbConnSecret {
     }
 }
 
-pub async fn get_conn_info() -> Result<(DbConnSecret, Conns), Error> {
+/// Fetches the db connection info and the knock profiles.
+///
+/// Missing or unparsable knock profiles aren't an error here; it's logged and `None` is returned so
+/// the caller can decide whether there's anything to fall back to.
+pub async fn get_conn_info() -> Result<(DbConnSecret, Option<Vec<Profile>>), Error> {
     let client = aws_sdk_secretsmanager::Client::new(crate::aws::get_conf().await);
 
     info!("Getting info from secrets");
@@ -32,12 +36,41 @@ pub async fn get_conn_info() -> Result<(DbConnSecret, Conns), Error> {
     let conns_fut = client.get_secret_value().secret_id("pknockerConns").send();
 
     info!("Parsing db conn");
-    let db =
-        serde_json::from_str::<DbConnSecret>(db_fut.await?.secret_string().unwrap_or_default())?;
+    let db_resp = db_fut.await.map_err(Error::secrets)?;
+    let db = serde_json::from_str::<DbConnSecret>(db_resp.secret_string().unwrap_or_default())
+        .map_err(Error::secrets)?;
 
     info!("Parsing conn list");
-    let conns =
-        serde_json::from_str::<Conns>(conns_fut.await?.secret_string().unwrap_or_default())?;
+    let conns = match conns_fut.await {
+        Err(err) => {
+            error!("Couldn't get the conn list: {err:?}");
+            None
+        }
+        Ok(resp) => {
+            match serde_json::from_str::<KnockSecret>(resp.secret_string().unwrap_or_default()) {
+                Err(err) => {
+                    error!("Couldn't parse the conn list: {err:?}");
+                    None
+                }
+                Ok(secret) => {
+                    let mut profiles = secret.into_profiles();
+                    profiles.retain(|p| {
+                        if p.is_empty() {
+                            error!("Ignoring profile {} with an empty conn list", p.name);
+                        }
+                        !p.is_empty()
+                    });
+
+                    if profiles.is_empty() {
+                        error!("The conn list has no usable profiles");
+                        None
+                    } else {
+                        Some(profiles)
+                    }
+                }
+            }
+        }
+    };
 
     info!("Got info :: {conns:?}");
 
@@ -0,0 +1,164 @@
+use std::io::{BufRead, BufReader, Read};
+
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::AsyncPgConnection;
+use flate2::read::MultiGzDecoder;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc::Sender;
+use tokio_util::io::SyncIoBridge;
+use tracing::log::{debug, info};
+
+use crate::dry_run::DryRun;
+use crate::error::Error;
+use crate::models::NewBlock;
+use crate::parq::{WantFields, BATCH_SIZE};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Adds the REJECT records from a (possibly gzipped) space delimited flow log, read a line at a
+/// time so the log never has to fit in memory.
+///
+/// The first line is the header naming the fields in the order they appear, which lets custom
+/// log formats work too.
+pub async fn add_records(
+    body: impl AsyncRead + Send + Unpin + 'static,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    // Decompressing and reading lines block, so they get their own thread and hand back batches
+    let (batches, mut parsed) = tokio::sync::mpsc::channel(1);
+    let body = SyncIoBridge::new(body);
+    let parser = tokio::task::spawn_blocking(move || parse(body, batches));
+
+    while let Some(batch) = parsed.recv().await {
+        add_batch(batch, pool, dry_run).await?;
+    }
+
+    parser.await.map_err(Error::decode)?
+}
+
+/// Sends the blocks [`BATCH_SIZE`] at a time, stopping early once nothing is receiving them
+fn parse(body: impl Read, batches: Sender<Vec<NewBlock>>) -> Result<(), Error> {
+    let mut body = BufReader::new(body);
+    let text: Box<dyn BufRead> = if is_gzip(body.fill_buf().map_err(Error::decode)?) {
+        Box::new(BufReader::new(MultiGzDecoder::new(body)))
+    } else {
+        Box::new(body)
+    };
+
+    let mut lines = text.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| Error::decode("empty flow log"))?
+        .map_err(Error::decode)?;
+
+    let mut want_fields = WantFields::default();
+    for (idx, name) in header.split_ascii_whitespace().enumerate() {
+        want_fields.field(name.trim_start_matches("${").trim_end_matches('}'), idx);
+    }
+    let fields = want_fields.build()?;
+
+    let mut to_add = Vec::new();
+    for line in lines {
+        let line = line.map_err(Error::decode)?;
+        let cols: Vec<&str> = line.split_ascii_whitespace().collect();
+        if cols.is_empty() {
+            continue;
+        }
+
+        match fields.text_to_block(&cols) {
+            Err(e) => debug!("Error adding row - {e}"),
+
+            Ok(block) => to_add.push(block),
+        };
+
+        if to_add.len() == BATCH_SIZE && batches.blocking_send(std::mem::take(&mut to_add)).is_err()
+        {
+            return Ok(());
+        }
+    }
+
+    // An error receiving is returned by the receiver
+    let _ = batches.blocking_send(to_add);
+    Ok(())
+}
+
+/// Inserts at most [`BATCH_SIZE`] rows at a time, the same as a parquet batch
+async fn add_batch(
+    to_add: Vec<NewBlock>,
+    pool: &Pool<AsyncPgConnection>,
+    dry_run: Option<&DryRun>,
+) -> Result<(), Error> {
+    match dry_run {
+        Some(dry_run) => {
+            info!("Would add {} blocks", to_add.len());
+            dry_run.blocks(&to_add);
+            Ok(())
+        }
+        None => crate::db::add_blocks(to_add, pool).await,
+    }
+}
+
+#[inline]
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    const LOG: &str = "\
+version srcaddr dstaddr srcport dstport protocol start end action
+2 73.5.159.5 10.0.0.7 40001 7614 6 1682484986 1682484990 REJECT
+2 73.5.159.5 10.0.0.7 40002 443 6 1682484986 1682484990 ACCEPT
+
+2 2001:db8::1 2001:db8::7 40003 1234 17 1682484987 1682484991 REJECT
+";
+
+    fn parsed(data: Vec<u8>) -> Vec<NewBlock> {
+        let (batches, mut parsed) = tokio::sync::mpsc::channel(1);
+        let parser = std::thread::spawn(move || parse(data.as_slice(), batches));
+
+        let mut blocks = Vec::new();
+        while let Some(batch) = parsed.blocking_recv() {
+            blocks.extend(batch);
+        }
+        parser.join().unwrap().unwrap();
+        blocks
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn plain_logs_are_read_by_line() {
+        let blocks = parsed(LOG.as_bytes().to_vec());
+        let ports: Vec<(i32, Option<i32>)> = blocks.iter().map(|b| (b.port, b.src_port)).collect();
+        assert_eq!(ports, [(7614, Some(40001)), (1234, Some(40003))]);
+    }
+
+    #[test]
+    fn every_gzip_member_is_read() {
+        let (head, tail) = LOG.split_at(LOG.find("\n2 2001").unwrap() + 1);
+        let mut data = gzip(head);
+        data.extend(gzip(tail));
+
+        let ports: Vec<i32> = parsed(data).iter().map(|b| b.port).collect();
+        assert_eq!(ports, [7614, 1234]);
+    }
+
+    #[test]
+    fn empty_logs_are_an_error() {
+        let (batches, _parsed) = tokio::sync::mpsc::channel(1);
+        assert!(parse(&[][..], batches).is_err());
+    }
+}